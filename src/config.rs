@@ -17,23 +17,34 @@
 
 //! Initial configuration for a PBFT node
 
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::str::FromStr;
 use std::time::Duration;
 
+use log::Level;
+
 use sawtooth_sdk::consensus::{
     engine::{BlockId, PeerId},
     service::Service,
 };
 
+use crate::error::PbftError;
+#[cfg(feature = "fault-injection")]
+use crate::fault::FaultBehavior;
 use crate::timing::retry_until_ok;
 
 /// Contains the initial configuration loaded from on-chain settings and local configuration. The
 /// `members` list is required; all other settings are optional (defaults used in their absence)
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct PbftConfig {
     // Members of the PBFT network
     pub members: Vec<PeerId>,
 
+    /// Whether the members are managed externally (supplied with `with_peers` and updated with
+    /// `PbftNode::set_peers`, for instance by a peer discovery service) instead of being read from
+    /// the `sawtooth.consensus.pbft.members` setting; if true, the on-chain members are ignored
+    pub external_membership: bool,
+
     /// How long to wait in between trying to publish blocks
     pub block_publishing_delay: Duration,
 
@@ -55,21 +66,131 @@ pub struct PbftConfig {
     /// to commit a block)
     pub commit_timeout: Duration,
 
+    /// Whether the idle timeout may trigger a view change (detects a primary that doesn't propose
+    /// blocks)
+    pub idle_timeout_enabled: bool,
+
+    /// Whether the commit timeout may trigger a view change (detects a network that stalls while
+    /// performing consensus on a block)
+    pub commit_timeout_enabled: bool,
+
     /// When view changing, how long to wait for a valid NewView message before starting a
     /// different view change
     pub view_change_duration: Duration,
 
+    /// The minimum time between starting successive view changes; a view change that is
+    /// triggered sooner is deferred until the interval has passed
+    pub min_view_change_interval: Duration,
+
+    /// How long after starting up the node waits before its own idle and commit timeouts may
+    /// start a view change; until then, it only joins view changes started by other nodes
+    pub startup_grace_period: Duration,
+
+    /// How often the primary broadcasts a heartbeat when it has nothing else to send, so the
+    /// secondaries' idle timeouts don't expire while the primary is healthy (0 to disable)
+    pub heartbeat_interval: Duration,
+
+    /// How long heartbeats may keep postponing the idle timeout while the primary makes no
+    /// progress (no block is committed and the view doesn't change); after that, heartbeats no
+    /// longer restart the idle timeout, so a primary that is alive but never publishes a block is
+    /// still replaced
+    pub max_heartbeat_postponement: Duration,
+
+    /// The maximum random jitter (as a percentage of the timeout's duration) added each time a
+    /// timeout is started, so nodes' timeouts don't all expire at once
+    pub timeout_jitter: u64,
+
     /// How many blocks to commit before forcing a view change for fairness
     pub forced_view_change_interval: u64,
 
+    /// How many views beyond the node's current view a `ViewChange` from another node may be for;
+    /// `ViewChange`s for later views are ignored, so a faulty node can't fill the log with them
+    /// (0 for no limit)
+    pub view_change_window: u64,
+
+    /// How many blocks may be committed without including a request that a node is tracking
+    /// before the node suspects the primary of censoring the request and starts a view change (0
+    /// to disable censorship detection). This isn't an on-chain setting, since the engine has no
+    /// evidence of client requests; it's for applications that embed the node and track requests
+    /// with `PbftNode::track_request`.
+    pub censorship_threshold: u64,
+
+    /// How long the primary waits before publishing an empty block (one whose summary is empty);
+    /// a non-empty block is published as soon as possible. Must be shorter than idle_timeout, so
+    /// the primary still publishes blocks often enough to not be considered faulty.
+    pub empty_block_delay: Duration,
+
+    /// How many times to retry committing a block if the validator returns an error
+    pub commit_block_retries: u64,
+
+    /// How long to wait in between attempts to commit a block
+    pub commit_block_retry_delay: Duration,
+
+    /// How many times to retry initializing a block if the validator returns an error; if the
+    /// primary still can't initialize a block, it starts a view change
+    pub initialize_block_retries: u64,
+
+    /// How long to wait in between attempts to initialize a block
+    pub initialize_block_retry_delay: Duration,
+
+    /// Whether to tolerate duplicate `BlockNew`/`BlockValid` updates for the same block; if false
+    /// (strict matching), a duplicate `BlockValid` is treated as an error
+    pub lenient_block_matching: bool,
+
     /// How large the PbftLog is allowed to get before being pruned
     pub max_log_size: u64,
 
+    /// How many of the most recent sequence numbers to keep a trace of phase transitions for, for
+    /// debugging (0 to not record traces)
+    pub phase_trace_size: u64,
+
+    /// The largest block payload (in bytes) the node will perform consensus on; larger blocks are
+    /// ignored, and the primary is suspected of being faulty if it published one for the current
+    /// sequence number (0 for no limit). The payload is the block's consensus data (the seal),
+    /// which is the only part of a block whose size the consensus engine sees; the summary is a
+    /// fixed-size digest of the batches.
+    pub max_block_payload_bytes: u64,
+
+    /// The maximum broadcast amplification (number of sends per unique message over a window of
+    /// recent sends); once this is reached, repeated sends of the same message are throttled (0
+    /// to never throttle sends). Throttling also drops legitimate re-sends, such as seal replies
+    /// and bootstrap commits to reconnecting peers, so it is disabled by default.
+    pub max_broadcast_amplification: f64,
+
     /// Where to store PbftState ("memory" or "disk+/path/to/file")
     pub storage_location: String,
+
+    /// How much of the per-message logging (the lines logged for each message that is sent or
+    /// received) to emit, independently of the global log level
+    pub log_verbosity: LogVerbosity,
+
+    /// How the primary is chosen for each view
+    pub primary_selection: PrimarySelection,
+
+    /// How many matching messages are needed for each kind of quorum
+    pub thresholds: PbftThresholds,
+
+    /// The number of votes each member has when counting Prepare, Commit, and ViewChange quorums;
+    /// members that aren't listed have one vote. With weights, `f` is the largest total weight of
+    /// faulty members that can be tolerated (`(total weight - 1) / 3`), and the quorums are
+    /// measured in votes instead of nodes.
+    pub vote_weights: HashMap<PeerId, u32>,
+
+    /// The faulty behavior to simulate, if any (only used for testing)
+    #[cfg(feature = "fault-injection")]
+    pub fault_behavior: Option<FaultBehavior>,
 }
 
 impl PbftConfig {
+    /// Use the given list of members instead of one from the on-chain settings, for instance when
+    /// the members are supplied by a peer discovery service; this makes the membership externally
+    /// managed (see `external_membership`)
+    pub fn with_peers(mut self, peers: Vec<PeerId>) -> Self {
+        self.members = peers;
+        self.external_membership = true;
+        self
+    }
+
     /// Load configuration from on-chain Sawtooth settings.
     ///
     /// Configuration loads the following settings:
@@ -77,12 +198,41 @@ impl PbftConfig {
     /// + `sawtooth.consensus.pbft.block_publishing_delay` (optional, default 1000 ms)
     /// + `sawtooth.consensus.pbft.idle_timeout` (optional, default 30000 ms)
     /// + `sawtooth.consensus.pbft.commit_timeout` (optional, default 10000 ms)
+    /// + `sawtooth.consensus.pbft.idle_timeout_enabled` (optional, default true)
+    /// + `sawtooth.consensus.pbft.commit_timeout_enabled` (optional, default true)
     /// + `sawtooth.consensus.pbft.view_change_duration` (optional, default 5000 ms)
+    /// + `sawtooth.consensus.pbft.min_view_change_interval` (optional, default 0 ms)
+    /// + `sawtooth.consensus.pbft.startup_grace_period` (optional, default 0 ms)
+    /// + `sawtooth.consensus.pbft.timeout_jitter` (optional, default 0 percent)
+    /// + `sawtooth.consensus.pbft.heartbeat_interval` (optional, default 0 ms)
+    /// + `sawtooth.consensus.pbft.max_heartbeat_postponement` (optional, default 300000 ms)
     /// + `sawtooth.consensus.pbft.forced_view_change_interval` (optional, default 100 blocks)
+    /// + `sawtooth.consensus.pbft.view_change_window` (optional, default 100 views)
+    /// + `sawtooth.consensus.pbft.empty_block_delay` (optional, default 0 ms)
+    /// + `sawtooth.consensus.pbft.commit_block_retries` (optional, default 3)
+    /// + `sawtooth.consensus.pbft.commit_block_retry_delay` (optional, default 100 ms)
+    /// + `sawtooth.consensus.pbft.initialize_block_retries` (optional, default 3)
+    /// + `sawtooth.consensus.pbft.initialize_block_retry_delay` (optional, default 100 ms)
+    /// + `sawtooth.consensus.pbft.max_broadcast_amplification` (optional, default 0, for no
+    ///   throttling)
+    /// + `sawtooth.consensus.pbft.lenient_block_matching` (optional, default false)
+    /// + `sawtooth.consensus.pbft.max_block_payload_bytes` (optional, default 0, for no limit)
+    /// + `sawtooth.consensus.pbft.phase_trace_size` (optional, default 0, for no phase traces)
+    /// + `sawtooth.consensus.pbft.primary_selection` (optional, default `round_robin`; see
+    ///   `PrimarySelection::from_str`)
+    /// + `sawtooth.consensus.pbft.prepared_threshold` (optional, default `2f+1`)
+    /// + `sawtooth.consensus.pbft.committed_threshold` (optional, default `2f+1`)
+    /// + `sawtooth.consensus.pbft.view_change_trigger_threshold` (optional, default `f+1`)
+    /// + `sawtooth.consensus.pbft.vote_weights` (optional, default `{}`; a JSON object mapping
+    ///   hex-encoded member IDs to their number of votes)
+    ///
+    /// If the membership is externally managed, the `sawtooth.consensus.pbft.members` setting is
+    /// ignored and the supplied members are kept.
     ///
     /// # Panics
-    /// + If block publishing delay is greater than the idle timeout
-    /// + If the `sawtooth.consensus.pbft.members` setting is not provided or is invalid
+    /// + If the loaded configuration is invalid (see `PbftConfig::validate`)
+    /// + If the membership isn't externally managed and the `sawtooth.consensus.pbft.members`
+    ///   setting is not provided or is invalid
     pub fn load_settings(&mut self, block_id: BlockId, service: &mut dyn Service) {
         debug!("Getting on-chain settings for config");
         let settings: HashMap<String, String> = retry_until_ok(
@@ -96,8 +246,30 @@ impl PbftConfig {
                         String::from("sawtooth.consensus.pbft.block_publishing_delay"),
                         String::from("sawtooth.consensus.pbft.idle_timeout"),
                         String::from("sawtooth.consensus.pbft.commit_timeout"),
+                        String::from("sawtooth.consensus.pbft.idle_timeout_enabled"),
+                        String::from("sawtooth.consensus.pbft.commit_timeout_enabled"),
                         String::from("sawtooth.consensus.pbft.view_change_duration"),
+                        String::from("sawtooth.consensus.pbft.min_view_change_interval"),
+                        String::from("sawtooth.consensus.pbft.startup_grace_period"),
+                        String::from("sawtooth.consensus.pbft.timeout_jitter"),
+                        String::from("sawtooth.consensus.pbft.heartbeat_interval"),
+                        String::from("sawtooth.consensus.pbft.max_heartbeat_postponement"),
                         String::from("sawtooth.consensus.pbft.forced_view_change_interval"),
+                        String::from("sawtooth.consensus.pbft.view_change_window"),
+                        String::from("sawtooth.consensus.pbft.empty_block_delay"),
+                        String::from("sawtooth.consensus.pbft.commit_block_retries"),
+                        String::from("sawtooth.consensus.pbft.commit_block_retry_delay"),
+                        String::from("sawtooth.consensus.pbft.initialize_block_retries"),
+                        String::from("sawtooth.consensus.pbft.initialize_block_retry_delay"),
+                        String::from("sawtooth.consensus.pbft.max_broadcast_amplification"),
+                        String::from("sawtooth.consensus.pbft.lenient_block_matching"),
+                        String::from("sawtooth.consensus.pbft.max_block_payload_bytes"),
+                        String::from("sawtooth.consensus.pbft.phase_trace_size"),
+                        String::from("sawtooth.consensus.pbft.primary_selection"),
+                        String::from("sawtooth.consensus.pbft.prepared_threshold"),
+                        String::from("sawtooth.consensus.pbft.committed_threshold"),
+                        String::from("sawtooth.consensus.pbft.view_change_trigger_threshold"),
+                        String::from("sawtooth.consensus.pbft.vote_weights"),
                     ],
                 )
             },
@@ -105,7 +277,10 @@ impl PbftConfig {
 
         // Get the on-chain list of PBFT members or panic if it is not provided; the network cannot
         // function without this setting, since there is no way of knowing which nodes are members.
-        self.members = get_members_from_settings(&settings);
+        // Externally managed members were already supplied.
+        if !self.external_membership {
+            self.members = get_members_from_settings(&settings);
+        }
 
         // Get durations
         merge_millis_setting_if_set(
@@ -128,21 +303,618 @@ impl PbftConfig {
             &mut self.view_change_duration,
             "sawtooth.consensus.pbft.view_change_duration",
         );
+        merge_millis_setting_if_set(
+            &settings,
+            &mut self.min_view_change_interval,
+            "sawtooth.consensus.pbft.min_view_change_interval",
+        );
+        merge_millis_setting_if_set(
+            &settings,
+            &mut self.startup_grace_period,
+            "sawtooth.consensus.pbft.startup_grace_period",
+        );
+        merge_millis_setting_if_set(
+            &settings,
+            &mut self.heartbeat_interval,
+            "sawtooth.consensus.pbft.heartbeat_interval",
+        );
+        merge_millis_setting_if_set(
+            &settings,
+            &mut self.max_heartbeat_postponement,
+            "sawtooth.consensus.pbft.max_heartbeat_postponement",
+        );
+        merge_millis_setting_if_set(
+            &settings,
+            &mut self.empty_block_delay,
+            "sawtooth.consensus.pbft.empty_block_delay",
+        );
+        merge_millis_setting_if_set(
+            &settings,
+            &mut self.commit_block_retry_delay,
+            "sawtooth.consensus.pbft.commit_block_retry_delay",
+        );
+        merge_millis_setting_if_set(
+            &settings,
+            &mut self.initialize_block_retry_delay,
+            "sawtooth.consensus.pbft.initialize_block_retry_delay",
+        );
 
-        // Check to make sure block_publishing_delay < idle_timeout
+        // Get flags
+        merge_setting_if_set(
+            &settings,
+            &mut self.idle_timeout_enabled,
+            "sawtooth.consensus.pbft.idle_timeout_enabled",
+        );
+        merge_setting_if_set(
+            &settings,
+            &mut self.commit_timeout_enabled,
+            "sawtooth.consensus.pbft.commit_timeout_enabled",
+        );
+        merge_setting_if_set(
+            &settings,
+            &mut self.lenient_block_matching,
+            "sawtooth.consensus.pbft.lenient_block_matching",
+        );
+
+        // Get integer constants
+        merge_setting_if_set(
+            &settings,
+            &mut self.forced_view_change_interval,
+            "sawtooth.consensus.pbft.forced_view_change_interval",
+        );
+        merge_setting_if_set(
+            &settings,
+            &mut self.view_change_window,
+            "sawtooth.consensus.pbft.view_change_window",
+        );
+        merge_setting_if_set(
+            &settings,
+            &mut self.commit_block_retries,
+            "sawtooth.consensus.pbft.commit_block_retries",
+        );
+        merge_setting_if_set(
+            &settings,
+            &mut self.initialize_block_retries,
+            "sawtooth.consensus.pbft.initialize_block_retries",
+        );
+        merge_setting_if_set(
+            &settings,
+            &mut self.max_broadcast_amplification,
+            "sawtooth.consensus.pbft.max_broadcast_amplification",
+        );
+        merge_setting_if_set(
+            &settings,
+            &mut self.timeout_jitter,
+            "sawtooth.consensus.pbft.timeout_jitter",
+        );
+        merge_setting_if_set(
+            &settings,
+            &mut self.max_block_payload_bytes,
+            "sawtooth.consensus.pbft.max_block_payload_bytes",
+        );
+        merge_setting_if_set(
+            &settings,
+            &mut self.phase_trace_size,
+            "sawtooth.consensus.pbft.phase_trace_size",
+        );
+
+        // Get the settings that every node must agree on for the network to select the same
+        // primaries and form the same quorums
+        merge_setting_if_set(
+            &settings,
+            &mut self.primary_selection,
+            "sawtooth.consensus.pbft.primary_selection",
+        );
+        merge_setting_if_set(
+            &settings,
+            &mut self.thresholds.prepared,
+            "sawtooth.consensus.pbft.prepared_threshold",
+        );
+        merge_setting_if_set(
+            &settings,
+            &mut self.thresholds.committed,
+            "sawtooth.consensus.pbft.committed_threshold",
+        );
+        merge_setting_if_set(
+            &settings,
+            &mut self.thresholds.view_change_trigger,
+            "sawtooth.consensus.pbft.view_change_trigger_threshold",
+        );
+        merge_setting_if_set_and_map(
+            &settings,
+            &mut self.vote_weights,
+            "sawtooth.consensus.pbft.vote_weights",
+            |VoteWeights(weights)| weights,
+        );
+
+        self.validate()
+            .unwrap_or_else(|err| panic!("Invalid PBFT configuration: {}", err));
+    }
+
+    /// Check that the configuration can be used to run PBFT:
+    /// + There must be at least 4 members (`3f + 1` with `f >= 1`), with no duplicates
+    /// + The forced view change interval, censorship threshold, and max log size must be positive
+    /// + The block publishing delay and empty block delay must be less than the idle timeout
+    /// + The commit and view change timeouts must be non-zero
+    /// + The exponential retry base must not be greater than the max
+    /// + The max broadcast amplification must be 0 (disabled) or at least 1
+    /// + The primary selection strategy must be able to select a primary
+    /// + Vote weights must be positive and only be given for members
+    ///
+    /// Thresholds that are weaker than the PBFT defaults are allowed, but a warning is logged.
+    pub fn validate(&self) -> Result<(), PbftError> {
+        if self.members.len() < 4 {
+            return Err(PbftError::InternalError(format!(
+                "PBFT requires at least 4 members (3f + 1 with f >= 1), but {} were provided",
+                self.members.len()
+            )));
+        }
+        let unique_members = self.members.iter().collect::<HashSet<_>>();
+        if unique_members.len() != self.members.len() {
+            return Err(PbftError::InternalError(
+                "The list of PBFT members contains duplicates".into(),
+            ));
+        }
+        if self.forced_view_change_interval == 0 {
+            return Err(PbftError::InternalError(
+                "The forced view change interval must be greater than 0".into(),
+            ));
+        }
+        if self.max_log_size == 0 {
+            return Err(PbftError::InternalError(
+                "The max log size must be greater than 0".into(),
+            ));
+        }
         if self.block_publishing_delay >= self.idle_timeout {
-            panic!(
+            return Err(PbftError::InternalError(format!(
                 "Block publishing delay ({:?}) must be less than the idle timeout ({:?})",
                 self.block_publishing_delay, self.idle_timeout
+            )));
+        }
+        if self.empty_block_delay >= self.idle_timeout {
+            return Err(PbftError::InternalError(format!(
+                "Empty block delay ({:?}) must be less than the idle timeout ({:?})",
+                self.empty_block_delay, self.idle_timeout
+            )));
+        }
+        if self.heartbeat_interval >= self.idle_timeout {
+            return Err(PbftError::InternalError(format!(
+                "Heartbeat interval ({:?}) must be less than the idle timeout ({:?})",
+                self.heartbeat_interval, self.idle_timeout
+            )));
+        }
+        if self.commit_timeout == Duration::from_millis(0) {
+            return Err(PbftError::InternalError(
+                "The commit timeout must be greater than 0".into(),
+            ));
+        }
+        if self.view_change_duration == Duration::from_millis(0) {
+            return Err(PbftError::InternalError(
+                "The view change duration must be greater than 0".into(),
+            ));
+        }
+        if self.exponential_retry_base > self.exponential_retry_max {
+            return Err(PbftError::InternalError(format!(
+                "Exponential retry base ({:?}) must not be greater than the max ({:?})",
+                self.exponential_retry_base, self.exponential_retry_max
+            )));
+        }
+        if self.max_broadcast_amplification != 0.0 && self.max_broadcast_amplification < 1.0 {
+            return Err(PbftError::InternalError(format!(
+                "Max broadcast amplification ({}) must be 0 or at least 1",
+                self.max_broadcast_amplification
+            )));
+        }
+        if self.timeout_jitter > 100 {
+            return Err(PbftError::InternalError(format!(
+                "Timeout jitter ({}%) must not be more than 100%",
+                self.timeout_jitter
+            )));
+        }
+        match &self.primary_selection {
+            PrimarySelection::RoundRobin => {}
+            PrimarySelection::Weighted(weights) => {
+                if weights.len() != self.members.len() || weights.iter().all(|w| *w == 0) {
+                    return Err(PbftError::InternalError(format!(
+                        "Weighted primary selection requires a weight for each of the {} members, \
+                         with at least one non-zero weight; got {:?}",
+                        self.members.len(),
+                        weights
+                    )));
+                }
+            }
+            PrimarySelection::Blacklist(blacklist) => {
+                if self.members.iter().all(|id| blacklist.contains(id)) {
+                    return Err(PbftError::InternalError(
+                        "Blacklist primary selection must leave at least one member eligible to \
+                         be primary"
+                            .into(),
+                    ));
+                }
+            }
+        }
+        if let Some((id, weight)) = self
+            .vote_weights
+            .iter()
+            .find(|(id, weight)| **weight == 0 || !self.members.contains(id))
+        {
+            return Err(PbftError::InternalError(format!(
+                "Vote weight {} for {} is invalid; vote weights must be positive and only be \
+                 given for members",
+                weight,
+                hex::encode(id)
+            )));
+        }
+        let unsafe_thresholds = self.thresholds.unsafe_thresholds();
+        if !unsafe_thresholds.is_empty() {
+            warn!(
+                "PBFT is configured with unsafe quorum thresholds ({}); conflicting blocks may be \
+                 committed if any node is faulty",
+                unsafe_thresholds.join(", ")
             );
         }
+        Ok(())
+    }
+}
 
-        // Get integer constants
+/// The number of matching messages from different nodes that make up a quorum, in terms of the
+/// maximum number of faulty nodes `f`
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum QuorumSize {
+    /// `f + 1` messages; at least one of them is from a non-faulty node
+    FPlusOne,
+    /// `2f + 1` messages; any two such quorums share at least one non-faulty node
+    TwoFPlusOne,
+}
+
+impl QuorumSize {
+    /// Get the number of messages in the quorum for the given `f`
+    pub fn size(self, f: u64) -> u64 {
+        match self {
+            QuorumSize::FPlusOne => f + 1,
+            QuorumSize::TwoFPlusOne => 2 * f + 1,
+        }
+    }
+
+    /// Get the number of votes in the quorum for members that have the given number of votes in
+    /// total; `f` is the largest total weight of faulty members that can be tolerated
+    pub fn votes(self, total_weight: u64) -> u64 {
+        self.size(total_weight.saturating_sub(1) / 3)
+    }
+}
+
+impl FromStr for QuorumSize {
+    type Err = PbftError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "f+1" => Ok(QuorumSize::FPlusOne),
+            "2f+1" => Ok(QuorumSize::TwoFPlusOne),
+            _ => Err(PbftError::InternalError(format!(
+                "Invalid quorum size: {} (expected 'f+1' or '2f+1')",
+                s
+            ))),
+        }
+    }
+}
+
+/// The quorum sizes used by the PBFT algorithm; the defaults are the values from the PBFT paper,
+/// and they should only be changed for deployments with a different threat model.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct PbftThresholds {
+    /// How many matching `Prepare`s make a block prepared (default `2f + 1`)
+    pub prepared: QuorumSize,
+    /// How many matching `Commit`s make a block committable; this is also the number of votes a
+    /// consensus seal must contain, counting the implicit vote of the block's publisher (default
+    /// `2f + 1`)
+    pub committed: QuorumSize,
+    /// How many `ViewChange`s for a view are needed for a node to join the view change before its
+    /// own timeouts expire (default `f + 1`)
+    pub view_change_trigger: QuorumSize,
+}
+
+impl Default for PbftThresholds {
+    fn default() -> Self {
+        PbftThresholds {
+            prepared: QuorumSize::TwoFPlusOne,
+            committed: QuorumSize::TwoFPlusOne,
+            view_change_trigger: QuorumSize::FPlusOne,
+        }
+    }
+}
+
+impl PbftThresholds {
+    /// Get the names of the thresholds that are weaker than PBFT requires for safety; with an
+    /// `f + 1` prepared or committed quorum, two conflicting blocks may both reach a quorum
+    pub fn unsafe_thresholds(&self) -> Vec<&'static str> {
+        let mut unsafe_thresholds = vec![];
+        if self.prepared != QuorumSize::TwoFPlusOne {
+            unsafe_thresholds.push("prepared");
+        }
+        if self.committed != QuorumSize::TwoFPlusOne {
+            unsafe_thresholds.push("committed");
+        }
+        unsafe_thresholds
+    }
+}
+
+/// The strategy used to choose the primary for each view
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub enum PrimarySelection {
+    /// The members take turns being primary, in order (the primary for view `v` is member
+    /// `v % n`)
+    #[default]
+    RoundRobin,
+    /// Each member is primary for a number of views proportional to its weight (one weight per
+    /// member, in the same order as the members); in each rotation, member `i` is primary
+    /// `weights[i]` times, with the members interleaved
+    Weighted(Vec<u32>),
+    /// The members take turns being primary like `RoundRobin`, except the listed members are
+    /// skipped
+    Blacklist(Vec<PeerId>),
+}
+
+impl PrimarySelection {
+    /// Get the primary for the given view out of the given members
+    ///
+    /// If the strategy doesn't fit the members (for instance, the weights don't match the
+    /// members after a membership change, or every member is blacklisted), round robin is used.
+    pub fn primary<'a>(&self, members: &'a [PeerId], view: u64) -> &'a PeerId {
+        let round_robin = &members[(view % members.len() as u64) as usize];
+        match self {
+            PrimarySelection::RoundRobin => round_robin,
+            PrimarySelection::Weighted(weights) => {
+                if weights.len() != members.len() {
+                    return round_robin;
+                }
+                let rounds = weights.iter().cloned().max().unwrap_or(0);
+                let schedule = (0..rounds)
+                    .flat_map(|round| {
+                        weights
+                            .iter()
+                            .enumerate()
+                            .filter(move |(_, weight)| **weight > round)
+                            .map(|(i, _)| i)
+                    })
+                    .collect::<Vec<_>>();
+                if schedule.is_empty() {
+                    return round_robin;
+                }
+                &members[schedule[(view % schedule.len() as u64) as usize]]
+            }
+            PrimarySelection::Blacklist(blacklist) => {
+                let eligible = members
+                    .iter()
+                    .filter(|id| !blacklist.contains(id))
+                    .collect::<Vec<_>>();
+                if eligible.is_empty() {
+                    return round_robin;
+                }
+                eligible[(view % eligible.len() as u64) as usize]
+            }
+        }
+    }
+}
+
+impl FromStr for PrimarySelection {
+    type Err = PbftError;
+
+    /// Parse a primary selection strategy: `round_robin`, `weighted:` followed by a JSON list of
+    /// weights (e.g. `weighted:[3, 1, 1, 1]`), or `blacklist:` followed by a JSON list of
+    /// hex-encoded member IDs
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = |err: String| {
+            PbftError::InternalError(format!("Invalid primary selection {}: {}", s, err))
+        };
+
+        if s == "round_robin" {
+            Ok(PrimarySelection::RoundRobin)
+        } else if let Some(weights) = s.strip_prefix("weighted:") {
+            serde_json::from_str(weights)
+                .map(PrimarySelection::Weighted)
+                .map_err(|err| invalid(err.to_string()))
+        } else if let Some(blacklist) = s.strip_prefix("blacklist:") {
+            serde_json::from_str::<Vec<String>>(blacklist)
+                .map_err(|err| invalid(err.to_string()))?
+                .into_iter()
+                .map(|id| hex::decode(id).map_err(|err| invalid(err.to_string())))
+                .collect::<Result<_, _>>()
+                .map(PrimarySelection::Blacklist)
+        } else {
+            Err(invalid(
+                "expected 'round_robin', 'weighted:[...]', or 'blacklist:[...]'".into(),
+            ))
+        }
+    }
+}
+
+/// The on-chain settings that every node must agree on at a given block for the network to select
+/// the same primaries and form the same quorums. Unlike the rest of the configuration, these are
+/// read from the chain again each time a block is committed, so a change to them takes effect on
+/// every node at the same block.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ConsensusSettings {
+    /// Members of the PBFT network
+    pub members: Vec<PeerId>,
+
+    /// How the primary is chosen for each view
+    pub primary_selection: PrimarySelection,
+
+    /// How many matching messages are needed for each kind of quorum
+    pub thresholds: PbftThresholds,
+
+    /// The number of votes each member has when counting quorums (see `PbftConfig::vote_weights`)
+    pub vote_weights: HashMap<PeerId, u32>,
+}
+
+impl ConsensusSettings {
+    /// The keys of the on-chain settings that the consensus settings are read from
+    pub const KEYS: &'static [&'static str] = &[
+        "sawtooth.consensus.pbft.members",
+        "sawtooth.consensus.pbft.primary_selection",
+        "sawtooth.consensus.pbft.prepared_threshold",
+        "sawtooth.consensus.pbft.committed_threshold",
+        "sawtooth.consensus.pbft.view_change_trigger_threshold",
+        "sawtooth.consensus.pbft.vote_weights",
+    ];
+
+    /// Get the keys of the on-chain settings to request from the validator
+    pub fn keys() -> Vec<String> {
+        Self::KEYS.iter().map(|key| key.to_string()).collect()
+    }
+
+    /// Get the consensus settings from the given on-chain settings; return an error if the
+    /// `sawtooth.consensus.pbft.members` setting is unset, empty, or invalid. The other settings
+    /// have their default values if they are unset or invalid, so every node gets the same values
+    /// from the same block regardless of its local configuration.
+    pub fn from_settings(settings: &HashMap<String, String>) -> Result<Self, PbftError> {
+        Ok(Self::from_settings_with_members(
+            settings,
+            try_get_members_from_settings(settings)?,
+        ))
+    }
+
+    /// Get the consensus settings from the given on-chain settings, but with the given members
+    /// instead of the `sawtooth.consensus.pbft.members` setting (used when the membership is
+    /// externally managed)
+    pub fn from_settings_with_members(
+        settings: &HashMap<String, String>,
+        members: Vec<PeerId>,
+    ) -> Self {
+        let mut consensus_settings = ConsensusSettings {
+            members,
+            ..Default::default()
+        };
         merge_setting_if_set(
-            &settings,
-            &mut self.forced_view_change_interval,
-            "sawtooth.consensus.pbft.forced_view_change_interval",
+            settings,
+            &mut consensus_settings.primary_selection,
+            "sawtooth.consensus.pbft.primary_selection",
+        );
+        merge_setting_if_set(
+            settings,
+            &mut consensus_settings.thresholds.prepared,
+            "sawtooth.consensus.pbft.prepared_threshold",
+        );
+        merge_setting_if_set(
+            settings,
+            &mut consensus_settings.thresholds.committed,
+            "sawtooth.consensus.pbft.committed_threshold",
+        );
+        merge_setting_if_set(
+            settings,
+            &mut consensus_settings.thresholds.view_change_trigger,
+            "sawtooth.consensus.pbft.view_change_trigger_threshold",
+        );
+        merge_setting_if_set_and_map(
+            settings,
+            &mut consensus_settings.vote_weights,
+            "sawtooth.consensus.pbft.vote_weights",
+            |VoteWeights(weights)| weights,
         );
+        consensus_settings
+    }
+
+    /// Get the number of votes the given node has (1 unless a vote weight is set for it)
+    pub fn vote_weight(&self, id: &[u8]) -> u64 {
+        vote_weight(&self.vote_weights, id)
+    }
+
+    /// Get the number of votes the given nodes have together; each node is counted once, even if
+    /// it appears more than once
+    pub fn total_vote_weight<'a, I: IntoIterator<Item = &'a [u8]>>(&self, ids: I) -> u64 {
+        total_vote_weight(&self.vote_weights, ids)
+    }
+
+    /// Get the number of votes needed for a quorum of the given size among the members
+    pub fn quorum_votes(&self, size: QuorumSize) -> u64 {
+        size.votes(self.total_vote_weight(self.members.iter().map(Vec::as_slice)))
+    }
+}
+
+/// Get the number of votes the given node has, given the vote weights of the members (1 unless a
+/// vote weight is set for it)
+pub fn vote_weight(vote_weights: &HashMap<PeerId, u32>, id: &[u8]) -> u64 {
+    vote_weights
+        .get(id)
+        .map(|weight| u64::from(*weight))
+        .unwrap_or(1)
+}
+
+/// Get the number of votes the given nodes have together, given the vote weights of the members;
+/// each node is counted once, even if it appears more than once
+pub fn total_vote_weight<'a, I: IntoIterator<Item = &'a [u8]>>(
+    vote_weights: &HashMap<PeerId, u32>,
+    ids: I,
+) -> u64 {
+    ids.into_iter()
+        .collect::<HashSet<_>>()
+        .into_iter()
+        .map(|id| vote_weight(vote_weights, id))
+        .sum()
+}
+
+/// The vote weights from the `sawtooth.consensus.pbft.vote_weights` setting: a JSON object that
+/// maps hex-encoded member IDs to their number of votes
+struct VoteWeights(HashMap<PeerId, u32>);
+
+impl FromStr for VoteWeights {
+    type Err = PbftError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid =
+            |err: String| PbftError::InternalError(format!("Invalid vote weights {}: {}", s, err));
+
+        serde_json::from_str::<HashMap<String, u32>>(s)
+            .map_err(|err| invalid(err.to_string()))?
+            .into_iter()
+            .map(|(id, weight)| {
+                hex::decode(id)
+                    .map(|id| (id, weight))
+                    .map_err(|err| invalid(err.to_string()))
+            })
+            .collect::<Result<_, _>>()
+            .map(VoteWeights)
+    }
+}
+
+/// How much of the per-message logging a node emits; the per-message lines are the ones logged
+/// for each message the node sends or receives, which make up most of PBFT's log output
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LogVerbosity {
+    /// Don't log the per-message lines at all
+    Quiet,
+    /// Log the per-message lines at their usual (debug or trace) level
+    Normal,
+    /// Log the per-message lines at the info level, so they are shown without enabling debug or
+    /// trace logging for the whole process
+    Trace,
+}
+
+impl LogVerbosity {
+    /// Get the level to log a per-message line at, given its usual level, or `None` if it
+    /// shouldn't be logged
+    pub fn message_log_level(self, usual_level: Level) -> Option<Level> {
+        match self {
+            LogVerbosity::Quiet => None,
+            LogVerbosity::Normal => Some(usual_level),
+            LogVerbosity::Trace => Some(Level::Info),
+        }
+    }
+}
+
+impl FromStr for LogVerbosity {
+    type Err = PbftError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "quiet" => Ok(LogVerbosity::Quiet),
+            "normal" => Ok(LogVerbosity::Normal),
+            "trace" => Ok(LogVerbosity::Trace),
+            _ => Err(PbftError::InternalError(format!(
+                "Invalid log verbosity: {} (expected 'quiet', 'normal', or 'trace')",
+                s
+            ))),
+        }
     }
 }
 
@@ -150,16 +922,41 @@ impl Default for PbftConfig {
     fn default() -> Self {
         PbftConfig {
             members: Vec::new(),
+            external_membership: false,
             block_publishing_delay: Duration::from_millis(1000),
             update_recv_timeout: Duration::from_millis(10),
             exponential_retry_base: Duration::from_millis(100),
             exponential_retry_max: Duration::from_millis(60000),
             idle_timeout: Duration::from_millis(30000),
             commit_timeout: Duration::from_millis(10000),
+            idle_timeout_enabled: true,
+            commit_timeout_enabled: true,
             view_change_duration: Duration::from_millis(5000),
+            min_view_change_interval: Duration::from_millis(0),
+            startup_grace_period: Duration::from_millis(0),
+            heartbeat_interval: Duration::from_millis(0),
+            max_heartbeat_postponement: Duration::from_millis(300_000),
+            timeout_jitter: 0,
             forced_view_change_interval: 100,
+            view_change_window: 100,
+            censorship_threshold: 0,
+            empty_block_delay: Duration::from_millis(0),
+            commit_block_retries: 3,
+            commit_block_retry_delay: Duration::from_millis(100),
+            initialize_block_retries: 3,
+            initialize_block_retry_delay: Duration::from_millis(100),
+            lenient_block_matching: false,
             max_log_size: 10000,
+            phase_trace_size: 0,
+            max_block_payload_bytes: 0,
+            max_broadcast_amplification: 0.0,
             storage_location: "memory".into(),
+            primary_selection: PrimarySelection::RoundRobin,
+            thresholds: PbftThresholds::default(),
+            vote_weights: HashMap::new(),
+            log_verbosity: LogVerbosity::Normal,
+            #[cfg(feature = "fault-injection")]
+            fault_behavior: None,
         }
     }
 }
@@ -208,23 +1005,230 @@ fn merge_millis_setting_if_set(
 pub fn get_members_from_settings<S: std::hash::BuildHasher>(
     settings: &HashMap<String, String, S>,
 ) -> Vec<PeerId> {
-    let members_setting_value = settings
-        .get("sawtooth.consensus.pbft.members")
-        .expect("'sawtooth.consensus.pbft.members' is empty; this setting must exist to use PBFT");
+    try_get_members_from_settings(settings).unwrap_or_else(|err| panic!("{}", err))
+}
 
-    let members: Vec<String> = serde_json::from_str(members_setting_value).unwrap_or_else(|err| {
-        panic!(
+/// Get the list of PBFT members as a Vec<PeerId> from settings; return an error if the
+/// `sawtooth.consensus.pbft.members` setting is unset, empty, or invalid
+pub fn try_get_members_from_settings<S: std::hash::BuildHasher>(
+    settings: &HashMap<String, String, S>,
+) -> Result<Vec<PeerId>, PbftError> {
+    let members_setting_value =
+        settings
+            .get("sawtooth.consensus.pbft.members")
+            .ok_or_else(|| {
+                PbftError::InternalError(
+            "'sawtooth.consensus.pbft.members' is empty; this setting must exist to use PBFT"
+                .into(),
+        )
+            })?;
+
+    let members: Vec<String> = serde_json::from_str(members_setting_value).map_err(|err| {
+        PbftError::InternalError(format!(
             "Unable to parse value at 'sawtooth.consensus.pbft.members' due to error: {:?}",
             err
-        )
-    });
+        ))
+    })?;
+
+    if members.is_empty() {
+        return Err(PbftError::InternalError(
+            "'sawtooth.consensus.pbft.members' does not contain any members".into(),
+        ));
+    }
 
     members
         .into_iter()
         .map(|s| {
-            hex::decode(s).unwrap_or_else(|err| {
-                panic!("Unable to parse PeerId from string due to error: {:?}", err)
+            hex::decode(s).map_err(|err| {
+                PbftError::InternalError(format!(
+                    "Unable to parse PeerId from string due to error: {:?}",
+                    err
+                ))
             })
         })
         .collect()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_helpers::*;
+
+    /// A `PbftConfig` that PBFT can't operate with should be detected when it is loaded, rather
+    /// than causing failures (such as dividing by zero or never being able to reach consensus)
+    /// later on. `PbftConfig::validate` checks the configuration and returns a descriptive error
+    /// for the first problem it finds.
+    ///
+    /// This test verifies that a valid configuration passes validation, and that each kind of
+    /// invalid configuration fails with the appropriate error message.
+    #[test]
+    fn test_config_validation() {
+        fn assert_invalid(config: PbftConfig, expected_error: &str) {
+            match config.validate() {
+                Err(PbftError::InternalError(err)) => {
+                    assert!(err.contains(expected_error), "Unexpected error: {}", err)
+                }
+                res => panic!(
+                    "Expected error containing {:?}, got {:?}",
+                    expected_error, res
+                ),
+            }
+        }
+
+        assert!(mock_config(4).validate().is_ok());
+        assert!(mock_config(7).validate().is_ok());
+
+        assert_invalid(mock_config(3), "requires at least 4 members");
+
+        let mut config = mock_config(4);
+        config.members[3] = config.members[0].clone();
+        assert_invalid(config, "contains duplicates");
+
+        let mut config = mock_config(4);
+        config.forced_view_change_interval = 0;
+        assert_invalid(config, "forced view change interval");
+
+        let mut config = mock_config(4);
+        config.max_log_size = 0;
+        assert_invalid(config, "max log size");
+
+        let mut config = mock_config(4);
+        config.block_publishing_delay = config.idle_timeout;
+        assert_invalid(config, "must be less than the idle timeout");
+
+        let mut config = mock_config(4);
+        config.empty_block_delay = config.idle_timeout;
+        assert_invalid(config, "Empty block delay");
+
+        let mut config = mock_config(4);
+        config.commit_timeout = Duration::from_millis(0);
+        assert_invalid(config, "commit timeout");
+
+        let mut config = mock_config(4);
+        config.view_change_duration = Duration::from_millis(0);
+        assert_invalid(config, "view change duration");
+
+        let mut config = mock_config(4);
+        config.exponential_retry_base = config.exponential_retry_max * 2;
+        assert_invalid(config, "Exponential retry base");
+
+        let mut config = mock_config(4);
+        config.max_broadcast_amplification = 0.5;
+        assert_invalid(config, "Max broadcast amplification");
+
+        let mut config = mock_config(4);
+        config.heartbeat_interval = config.idle_timeout;
+        assert_invalid(config, "Heartbeat interval");
+
+        let mut config = mock_config(4);
+        config.vote_weights.insert(vec![9], 2);
+        assert_invalid(config, "Vote weight 2");
+
+        let mut config = mock_config(4);
+        config.vote_weights.insert(vec![0], 0);
+        assert_invalid(config, "Vote weight 0");
+
+        let mut config = mock_config(4);
+        config.timeout_jitter = 101;
+        assert_invalid(config, "Timeout jitter");
+
+        let mut config = mock_config(4);
+        config.primary_selection = PrimarySelection::Weighted(vec![1, 1, 1]);
+        assert_invalid(config, "requires a weight for each");
+
+        let mut config = mock_config(4);
+        config.primary_selection = PrimarySelection::Weighted(vec![0, 0, 0, 0]);
+        assert_invalid(config, "requires a weight for each");
+
+        let mut config = mock_config(4);
+        config.primary_selection = PrimarySelection::Blacklist(config.members.clone());
+        assert_invalid(config, "at least one member eligible");
+    }
+
+    /// The log verbosity controls the per-message lines (such as "Got peer message") separately
+    /// from the global log level: `Quiet` suppresses them, `Normal` logs them at their usual level,
+    /// and `Trace` logs them at the info level so they're visible without debug logging.
+    ///
+    /// This test verifies the level each verbosity chooses for a per-message line that is usually
+    /// logged at the trace level, and that verbosities are parsed from their names.
+    #[test]
+    fn test_log_verbosity() {
+        assert_eq!(None, LogVerbosity::Quiet.message_log_level(Level::Trace));
+        assert_eq!(
+            Some(Level::Trace),
+            LogVerbosity::Normal.message_log_level(Level::Trace)
+        );
+        assert_eq!(
+            Some(Level::Info),
+            LogVerbosity::Trace.message_log_level(Level::Trace)
+        );
+        assert_eq!(LogVerbosity::Normal, PbftConfig::default().log_verbosity);
+
+        assert_eq!(LogVerbosity::Quiet, "quiet".parse().unwrap());
+        assert_eq!(LogVerbosity::Normal, "normal".parse().unwrap());
+        assert_eq!(LogVerbosity::Trace, "trace".parse().unwrap());
+        assert!("loud".parse::<LogVerbosity>().is_err());
+    }
+
+    /// Deployments can choose how the primary is selected for each view: round robin (the
+    /// default), weighted (so a known-reliable node is primary more often), or round robin with
+    /// a blacklist of nodes that may never be primary.
+    ///
+    /// This test verifies that each strategy chooses the expected primaries for a sequence of
+    /// views, that strategies that don't fit the members fall back to round robin, and that
+    /// strategies are parsed from their setting values.
+    #[test]
+    fn test_primary_selection() {
+        let members = mock_config(4).members;
+        let primaries = |selection: PrimarySelection, members: &[PeerId]| {
+            (0..8)
+                .map(|view| selection.primary(members, view).clone())
+                .collect::<Vec<_>>()
+        };
+        let ids = |indexes: &[usize]| {
+            indexes
+                .iter()
+                .map(|i| members[*i].clone())
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            ids(&[0, 1, 2, 3, 0, 1, 2, 3]),
+            primaries(PrimarySelection::RoundRobin, &members)
+        );
+        assert_eq!(
+            ids(&[0, 1, 2, 3, 0, 0, 0, 1]),
+            primaries(PrimarySelection::Weighted(vec![3, 1, 1, 1]), &members)
+        );
+        assert_eq!(
+            ids(&[0, 2, 3, 0, 2, 3, 0, 2]),
+            primaries(
+                PrimarySelection::Blacklist(vec![members[1].clone()]),
+                &members
+            )
+        );
+
+        // Fall back to round robin if the strategy doesn't fit the members
+        assert_eq!(
+            ids(&[0, 1, 2, 3, 0, 1, 2, 3]),
+            primaries(PrimarySelection::Weighted(vec![1, 1]), &members)
+        );
+        assert_eq!(
+            ids(&[0, 1, 2, 3, 0, 1, 2, 3]),
+            primaries(PrimarySelection::Blacklist(members.clone()), &members)
+        );
+
+        // Strategies are parsed from the `sawtooth.consensus.pbft.primary_selection` setting
+        assert_eq!(PrimarySelection::RoundRobin, "round_robin".parse().unwrap());
+        assert_eq!(
+            PrimarySelection::Weighted(vec![3, 1, 1, 1]),
+            "weighted:[3, 1, 1, 1]".parse().unwrap()
+        );
+        assert_eq!(
+            PrimarySelection::Blacklist(vec![vec![0xab, 0xcd]]),
+            "blacklist:[\"abcd\"]".parse().unwrap()
+        );
+        assert!("weighted".parse::<PrimarySelection>().is_err());
+        assert!("blacklist:[\"xyz\"]".parse::<PrimarySelection>().is_err());
+    }
+}
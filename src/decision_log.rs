@@ -0,0 +1,258 @@
+/*
+ * Copyright 2018 Bitwise IO, Inc.
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ * ------------------------------------------------------------------------------
+ */
+
+//! A record of the consensus decisions made by a node, which can be exported and verified
+
+use std::collections::HashSet;
+
+use sawtooth_sdk::consensus::engine::{BlockId, PeerId};
+
+use crate::config::{ConsensusSettings, QuorumSize};
+use crate::error::PbftError;
+use crate::message_type::PbftMessageType;
+use crate::node::PbftNode;
+use crate::protos::pbft_message::{PbftNewView, PbftSeal, PbftSignedVote};
+
+/// The kind of consensus decision
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum DecisionKind {
+    /// A block was committed; the certificate contains the Commit votes for the block
+    Commit,
+    /// The view was changed; the certificate contains the ViewChange votes from the NewView
+    ViewChange,
+}
+
+/// A signed vote from another node that is part of a decision's certificate
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct CertificateVote {
+    pub header_bytes: Vec<u8>,
+    pub header_signature: Vec<u8>,
+    pub message_bytes: Vec<u8>,
+}
+
+impl CertificateVote {
+    fn from_signed_vote(vote: &PbftSignedVote) -> Self {
+        CertificateVote {
+            header_bytes: vote.get_header_bytes().to_vec(),
+            header_signature: vote.get_header_signature().to_vec(),
+            message_bytes: vote.get_message_bytes().to_vec(),
+        }
+    }
+
+    fn to_signed_vote(&self) -> PbftSignedVote {
+        let mut vote = PbftSignedVote::new();
+        vote.set_header_bytes(self.header_bytes.clone());
+        vote.set_header_signature(self.header_signature.clone());
+        vote.set_message_bytes(self.message_bytes.clone());
+        vote
+    }
+}
+
+/// A consensus decision made by a node, along with the certificate that justifies it
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct DecisionRecord {
+    pub kind: DecisionKind,
+    /// The view the decision was made in (for view changes, the new view)
+    pub view: u64,
+    pub seq_num: u64,
+    /// The committed block (empty for view changes)
+    pub block_id: BlockId,
+    /// The node whose vote is implicit: the creator of the seal for commits, and the new primary
+    /// for view changes
+    #[serde(default)]
+    pub signer_id: PeerId,
+    pub certificate: Vec<CertificateVote>,
+}
+
+impl DecisionRecord {
+    /// Create a record of a block commit from the seal that proves it
+    pub fn from_seal(seal: &PbftSeal) -> Self {
+        DecisionRecord {
+            kind: DecisionKind::Commit,
+            view: seal.get_info().get_view(),
+            seq_num: seal.get_info().get_seq_num(),
+            block_id: seal.get_block_id().to_vec(),
+            signer_id: seal.get_info().get_signer_id().to_vec(),
+            certificate: seal
+                .get_commit_votes()
+                .iter()
+                .map(CertificateVote::from_signed_vote)
+                .collect(),
+        }
+    }
+
+    /// Create a record of a view change from the NewView message that completed it
+    pub fn from_new_view(new_view: &PbftNewView) -> Self {
+        DecisionRecord {
+            kind: DecisionKind::ViewChange,
+            view: new_view.get_info().get_view(),
+            seq_num: new_view.get_info().get_seq_num(),
+            block_id: BlockId::new(),
+            signer_id: new_view.get_info().get_signer_id().to_vec(),
+            certificate: new_view
+                .get_view_changes()
+                .iter()
+                .map(CertificateVote::from_signed_vote)
+                .collect(),
+        }
+    }
+}
+
+/// Verify a decision log exported by a node, given a function that returns the consensus
+/// settings (the members, primary selection, quorum thresholds, and vote weights) that were in
+/// effect when each decision was made; for instance, the on-chain settings at the block before
+/// the decision's sequence number, which are the settings a node uses to verify a seal
+///
+/// Each decision's certificate must contain properly signed votes from different members that
+/// match the decision; together with the implicit vote of the node that made the decision (the
+/// seal's creator for commits, and the new primary for view changes, whose votes can't be in the
+/// certificate), they must make a quorum: the `committed` threshold for commits, and `2f + 1` for
+/// view changes, counted with the vote weights. Commits must be in increasing order of sequence
+/// number, and views may never decrease; each view change must be to a later view.
+pub fn verify_decision_log<F>(
+    records: &[DecisionRecord],
+    mut consensus_settings: F,
+) -> Result<(), PbftError>
+where
+    F: FnMut(&DecisionRecord) -> Result<ConsensusSettings, PbftError>,
+{
+    let mut last_view = 0;
+    let mut last_seq_num = None;
+
+    for record in records {
+        match record.kind {
+            DecisionKind::Commit => {
+                if record.view < last_view {
+                    return Err(PbftError::InvalidMessage(format!(
+                        "Commit of block {} is in view {}, but view was already {}",
+                        record.seq_num, record.view, last_view
+                    )));
+                }
+                if matches!(last_seq_num, Some(seq_num) if record.seq_num <= seq_num) {
+                    return Err(PbftError::InvalidMessage(format!(
+                        "Commit of block {} is out of order",
+                        record.seq_num
+                    )));
+                }
+                last_seq_num = Some(record.seq_num);
+            }
+            DecisionKind::ViewChange => {
+                if record.view <= last_view {
+                    return Err(PbftError::InvalidMessage(format!(
+                        "View change to view {} is out of order (view was already {})",
+                        record.view, last_view
+                    )));
+                }
+            }
+        }
+        last_view = record.view;
+
+        verify_certificate(record, &consensus_settings(record)?)?;
+    }
+
+    Ok(())
+}
+
+/// Verify that the decision's certificate, along with the implicit vote of the node that made the
+/// decision, makes a quorum of valid votes from different members
+fn verify_certificate(
+    record: &DecisionRecord,
+    consensus_settings: &ConsensusSettings,
+) -> Result<(), PbftError> {
+    let (expected_type, quorum_size) = match record.kind {
+        DecisionKind::Commit => (
+            PbftMessageType::Commit,
+            consensus_settings.thresholds.committed,
+        ),
+        DecisionKind::ViewChange => (PbftMessageType::ViewChange, QuorumSize::TwoFPlusOne),
+    };
+    let members = &consensus_settings.members;
+
+    // The node that made the decision must be a member; for view changes, it must be the primary
+    // of the new view
+    if !members.contains(&record.signer_id) {
+        return Err(PbftError::InvalidMessage(format!(
+            "{:?} decision in view {} was made by a node that isn't a member: {:?}",
+            record.kind, record.view, record.signer_id
+        )));
+    }
+    if record.kind == DecisionKind::ViewChange
+        && &record.signer_id
+            != consensus_settings
+                .primary_selection
+                .primary(members, record.view)
+    {
+        return Err(PbftError::InvalidMessage(format!(
+            "View change to view {} was made by a node that isn't its primary: {:?}",
+            record.view, record.signer_id
+        )));
+    }
+
+    let voter_ids = record
+        .certificate
+        .iter()
+        .try_fold(HashSet::new(), |mut ids, vote| {
+            PbftNode::verify_vote(&vote.to_signed_vote(), expected_type, |msg| {
+                if msg.get_info().get_view() != record.view
+                    || (record.kind == DecisionKind::Commit
+                        && (msg.get_info().get_seq_num() != record.seq_num
+                            || msg.get_block_id() != record.block_id.as_slice()))
+                {
+                    return Err(PbftError::InvalidMessage(format!(
+                        "{:?} vote doesn't match the decision: {:?}",
+                        expected_type, msg
+                    )));
+                }
+                Ok(())
+            })
+            .map(|id| ids.insert(id))?;
+            Ok(ids)
+        })?;
+
+    // The vote of the node that made the decision is implicit, so it can't be in the certificate
+    let member_ids: HashSet<PeerId> = members
+        .iter()
+        .filter(|id| **id != record.signer_id)
+        .cloned()
+        .collect();
+
+    if !voter_ids.is_subset(&member_ids) {
+        return Err(PbftError::InvalidMessage(format!(
+            "Certificate for {:?} decision in view {} contains vote(s) from invalid IDs: {:?}",
+            record.kind,
+            record.view,
+            voter_ids.difference(&member_ids).collect::<Vec<_>>()
+        )));
+    }
+
+    let required_votes = consensus_settings.quorum_votes(quorum_size);
+    let votes = consensus_settings.total_vote_weight(
+        voter_ids
+            .iter()
+            .map(Vec::as_slice)
+            .chain(std::iter::once(record.signer_id.as_slice())),
+    );
+    if votes < required_votes {
+        return Err(PbftError::InvalidMessage(format!(
+            "Certificate for {:?} decision in view {} needs {} votes (including the implicit vote \
+             of the node that made it), but only {} found",
+            record.kind, record.view, required_votes, votes
+        )));
+    }
+
+    Ok(())
+}
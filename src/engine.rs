@@ -23,11 +23,13 @@ use sawtooth_sdk::consensus::{engine::*, service::Service};
 
 use crate::config::PbftConfig;
 use crate::error::PbftError;
-use crate::message_type::ParsedMessage;
+use crate::message_type::{ParsedMessage, PbftMessageType};
 use crate::node::PbftNode;
-use crate::state::{PbftMode, PbftState};
+use crate::state::PbftState;
 use crate::storage::get_storage;
-use crate::timing;
+
+/// The most updates from the validator that are handled at once (see `handle_updates`)
+const MAX_UPDATE_BATCH: usize = 100;
 
 pub struct PbftEngine {
     config: PbftConfig,
@@ -71,8 +73,6 @@ impl Engine for PbftEngine {
 
         info!("PBFT state created: {}", **pbft_state.read());
 
-        let mut block_publishing_ticker = timing::Ticker::new(self.config.block_publishing_delay);
-
         let mut node = PbftNode::new(
             &self.config,
             chain_head,
@@ -83,50 +83,32 @@ impl Engine for PbftEngine {
 
         node.start_idle_timeout(&mut pbft_state.write());
 
-        // Main event loop; keep going until PBFT receives a Shutdown message or is disconnected
+        // Main event loop; keep going until PBFT receives a Shutdown message or is disconnected.
+        // Any updates that are already waiting are handled along with the one that was received,
+        // so a burst of messages can be handled as a batch.
         loop {
             let incoming_message = updates.recv_timeout(self.config.update_recv_timeout);
             let state = &mut **pbft_state.write();
 
             trace!("{} received message {:?}", state, incoming_message);
 
-            match handle_update(&mut node, incoming_message, state) {
-                Ok(again) => {
-                    if !again {
-                        break;
-                    }
-                }
-                Err(err) => log_any_error(Err(err)),
-            }
-
-            // If the block publishing delay has passed, attempt to publish a block
-            block_publishing_ticker.tick(|| log_any_error(node.try_publish(state)));
-
-            // If the idle timeout has expired, initiate a view change
-            if node.check_idle_timeout_expired(state) {
-                warn!("Idle timeout expired; proposing view change");
-                log_any_error(node.start_view_change(state, state.view + 1));
+            let mut incoming_messages = vec![incoming_message];
+            if incoming_messages[0].is_ok() {
+                incoming_messages.extend(updates.try_iter().take(MAX_UPDATE_BATCH - 1).map(Ok));
             }
-
-            // If the commit timeout has expired, initiate a view change
-            if node.check_commit_timeout_expired(state) {
-                warn!("Commit timeout expired; proposing view change");
-                log_any_error(node.start_view_change(state, state.view + 1));
+            if !handle_updates(&mut node, incoming_messages, state) {
+                break;
             }
 
-            // Check the view change timeout if the node is view changing so we can start a new
-            // view change if we don't get a NewView in time
-            if let PbftMode::ViewChanging(v) = state.mode {
-                if node.check_view_change_timeout_expired(state) {
-                    warn!(
-                        "View change timeout expired; proposing view change for view {}",
-                        v + 1
-                    );
-                    log_any_error(node.start_view_change(state, v + 1));
-                }
+            // Do the node's periodic work (deferred view changes, heartbeats, publishing, and
+            // timeouts)
+            for res in node.handle_tick(state) {
+                log_any_error(res.map(|_| ()));
             }
         }
 
+        node.shutdown(&mut *pbft_state);
+
         Ok(())
     }
 
@@ -151,25 +133,11 @@ fn handle_update(
     match incoming_message {
         Ok(Update::BlockNew(block)) => node.on_block_new(block, state)?,
         Ok(Update::BlockValid(block_id)) => node.on_block_valid(block_id, state)?,
-        Ok(Update::BlockInvalid(block_id)) => node.on_block_invalid(block_id)?,
+        Ok(Update::BlockInvalid(block_id)) => node.on_block_invalid(block_id, state)?,
         Ok(Update::BlockCommit(block_id)) => node.on_block_commit(block_id, state)?,
         Ok(Update::PeerMessage(message, _)) => {
-            // Since the signer ID in the PeerMessageHeader is verified by the validator, it can be
-            // ensured that the PbftMessage was in fact created and signed by the node that it
-            // claims to be from by comparing the header's signer and the PbftMessage's signer
-            let verified_signer_id = message.header.signer_id.clone();
-            let parsed_message = ParsedMessage::from_peer_message(message, state.id.as_slice())?;
-            let pbft_signer_id = parsed_message.info().get_signer_id().to_vec();
-
-            if pbft_signer_id != verified_signer_id {
-                return Err(PbftError::InvalidMessage(format!(
-                    "Mismatch between PbftMessage's signer ID ({:?}) and PeerMessage's signer ID \
-                     ({:?}) of peer message: {:?}",
-                    pbft_signer_id, verified_signer_id, parsed_message
-                )));
-            }
-
-            node.on_peer_message(parsed_message, state)?
+            let parsed_message = parse_peer_message(node, message, state)?;
+            node.on_peer_message(parsed_message, state)?;
         }
         Ok(Update::Shutdown) => {
             info!("Received shutdown; stopping PBFT");
@@ -192,6 +160,112 @@ fn handle_update(
     Ok(true)
 }
 
+/// Handle a batch of updates in order, logging any errors; consecutive peer messages are handled
+/// together with `PbftNode::on_peer_messages`, and consecutive block commits with
+/// `PbftNode::on_blocks_committed`. Returns `false` if the engine should stop.
+fn handle_updates(
+    node: &mut PbftNode,
+    incoming_messages: Vec<Result<Update, RecvTimeoutError>>,
+    state: &mut PbftState,
+) -> bool {
+    let mut peer_messages = vec![];
+    let mut block_commits = vec![];
+
+    for incoming_message in incoming_messages {
+        match incoming_message {
+            Ok(Update::PeerMessage(message, _)) => {
+                handle_block_commits(node, &mut block_commits, state);
+                match parse_peer_message(node, message, state) {
+                    Ok(parsed_message) => peer_messages.push(parsed_message),
+                    Err(err) => log_any_error(Err(err)),
+                }
+            }
+            Ok(Update::BlockCommit(block_id)) => {
+                handle_peer_messages(node, &mut peer_messages, state);
+                block_commits.push(block_id);
+            }
+            incoming_message => {
+                handle_peer_messages(node, &mut peer_messages, state);
+                handle_block_commits(node, &mut block_commits, state);
+                match handle_update(node, incoming_message, state) {
+                    Ok(again) => {
+                        if !again {
+                            return false;
+                        }
+                    }
+                    Err(err) => log_any_error(Err(err)),
+                }
+            }
+        }
+    }
+
+    handle_peer_messages(node, &mut peer_messages, state);
+    handle_block_commits(node, &mut block_commits, state);
+
+    true
+}
+
+/// Handle the peer messages that were collected from a batch of updates, if there are any
+fn handle_peer_messages(
+    node: &mut PbftNode,
+    peer_messages: &mut Vec<ParsedMessage>,
+    state: &mut PbftState,
+) {
+    if !peer_messages.is_empty() {
+        for res in node.on_peer_messages(std::mem::take(peer_messages), state) {
+            log_any_error(res);
+        }
+    }
+}
+
+/// Handle the block commits that were collected from a batch of updates, if there are any
+fn handle_block_commits(
+    node: &mut PbftNode,
+    block_commits: &mut Vec<BlockId>,
+    state: &mut PbftState,
+) {
+    if !block_commits.is_empty() {
+        for res in node.on_blocks_committed(std::mem::take(block_commits), state) {
+            log_any_error(res);
+        }
+    }
+}
+
+/// Parse the PBFT message in a peer message from the validator
+///
+/// Since the signer ID in the PeerMessageHeader is verified by the validator, it can be ensured
+/// that the PbftMessage was in fact created and signed by the node that it claims to be from by
+/// comparing the header's signer and the PbftMessage's signer.
+fn parse_peer_message(
+    node: &mut PbftNode,
+    message: PeerMessage,
+    state: &PbftState,
+) -> Result<ParsedMessage, PbftError> {
+    let verified_signer_id = message.header.signer_id.clone();
+    let parsed_message = ParsedMessage::from_peer_message(message, state.id.as_slice())?;
+    let pbft_signer_id = parsed_message.info().get_signer_id().to_vec();
+
+    if pbft_signer_id != verified_signer_id {
+        node.record_dropped_message(PbftMessageType::from(parsed_message.info().get_msg_type()));
+        return Err(PbftError::InvalidMessage(format!(
+            "Mismatch between PbftMessage's signer ID ({:?}) and PeerMessage's signer ID \
+             ({:?}) of peer message: {:?}",
+            pbft_signer_id, verified_signer_id, parsed_message
+        )));
+    }
+
+    Ok(parsed_message)
+}
+
+#[cfg(test)]
+pub fn test_handle_updates(
+    node: &mut PbftNode,
+    incoming_messages: Vec<Result<Update, RecvTimeoutError>>,
+    state: &mut PbftState,
+) -> bool {
+    handle_updates(node, incoming_messages, state)
+}
+
 #[cfg(test)]
 pub fn test_handle_update(
     node: &mut PbftNode,
@@ -207,7 +281,8 @@ fn log_any_error(res: Result<(), PbftError>) {
         match e {
             PbftError::SigningError(_)
             | PbftError::FaultyPrimary(_)
-            | PbftError::InvalidMessage(_) => warn!("{}", e),
+            | PbftError::InvalidMessage(_)
+            | PbftError::SequenceOutOfBounds(_) => warn!("{}", e),
             _ => error!("{}", e),
         }
     }
@@ -42,6 +42,14 @@ pub enum PbftError {
     /// An invalid message was received
     InvalidMessage(String),
 
+    /// A message or block has a sequence number the node can't handle yet, such as a PrePrepare
+    /// beyond the window of accepted sequence numbers or a block that doesn't follow the chain
+    /// head
+    SequenceOutOfBounds(String),
+
+    /// This node is not one of the PBFT members (description)
+    NodeNotMember(String),
+
     /// Internal PBFT error (description)
     InternalError(String),
 }
@@ -54,6 +62,8 @@ impl Error for PbftError {
             PbftError::SigningError(_) => None,
             PbftError::FaultyPrimary(_) => None,
             PbftError::InvalidMessage(_) => None,
+            PbftError::SequenceOutOfBounds(_) => None,
+            PbftError::NodeNotMember(_) => None,
             PbftError::InternalError(_) => None,
         }
     }
@@ -71,6 +81,8 @@ impl fmt::Display for PbftError {
                 description
             ),
             PbftError::InvalidMessage(description) => write!(f, "{}", description),
+            PbftError::SequenceOutOfBounds(description) => write!(f, "{}", description),
+            PbftError::NodeNotMember(description) => write!(f, "{}", description),
             PbftError::InternalError(description) => write!(f, "{}", description),
         }
     }
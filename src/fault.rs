@@ -0,0 +1,114 @@
+/*
+ * Copyright 2018 Bitwise IO, Inc.
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ * ------------------------------------------------------------------------------
+ */
+
+//! Fault injection for simulating Byzantine behavior in tests
+//!
+//! This module is only compiled with the `fault-injection` feature, so production builds are not
+//! affected by it.
+
+use std::thread::sleep;
+use std::time::Duration;
+
+use crate::message_type::PbftMessageType;
+use crate::protos::pbft_message::PbftMessage;
+
+/// A faulty behavior that a node can be configured to simulate when it broadcasts messages
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FaultBehavior {
+    /// Never broadcast PrePrepare messages (simulates a primary that doesn't propose blocks)
+    DropPrePrepare,
+    /// Broadcast a second, conflicting version of each message (for a different block)
+    Equivocate,
+    /// Wait the given number of milliseconds before broadcasting each Commit message
+    DelayCommit(u64),
+    /// Broadcast messages with the wrong sequence number
+    WrongSeqNum,
+}
+
+/// Apply the faulty behavior to a message that is about to be broadcast; returns the messages
+/// that should be broadcast instead
+pub fn apply_fault(behavior: FaultBehavior, msg: PbftMessage) -> Vec<PbftMessage> {
+    let msg_type = PbftMessageType::from(msg.get_info().get_msg_type());
+    match behavior {
+        FaultBehavior::DropPrePrepare if msg_type == PbftMessageType::PrePrepare => {
+            warn!("Fault injection: dropping {}", msg_type);
+            vec![]
+        }
+        FaultBehavior::Equivocate => {
+            warn!("Fault injection: equivocating on {}", msg_type);
+            let mut conflicting = msg.clone();
+            let mut block_id = conflicting.take_block_id();
+            block_id.push(0xff);
+            conflicting.set_block_id(block_id);
+            vec![msg, conflicting]
+        }
+        FaultBehavior::DelayCommit(millis) if msg_type == PbftMessageType::Commit => {
+            warn!("Fault injection: delaying {} by {} ms", msg_type, millis);
+            sleep(Duration::from_millis(millis));
+            vec![msg]
+        }
+        FaultBehavior::WrongSeqNum => {
+            warn!("Fault injection: changing sequence number of {}", msg_type);
+            let mut msg = msg;
+            let seq_num = msg.get_info().get_seq_num();
+            msg.mut_info().set_seq_num(seq_num + 1);
+            vec![msg]
+        }
+        _ => vec![msg],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protos::pbft_message::PbftMessageInfo;
+
+    fn mock_pbft_message(msg_type: PbftMessageType) -> PbftMessage {
+        let mut msg = PbftMessage::new();
+        msg.set_info(PbftMessageInfo::new_from(msg_type, 0, 1, vec![0]));
+        msg.set_block_id(vec![1]);
+        msg
+    }
+
+    /// Each faulty behavior should only alter the messages it applies to. Verify that
+    /// `DropPrePrepare` drops only PrePrepares, `Equivocate` adds a conflicting message,
+    /// `DelayCommit` leaves messages unchanged, and `WrongSeqNum` changes the sequence number.
+    #[test]
+    fn test_apply_fault() {
+        let pre_prepare = mock_pbft_message(PbftMessageType::PrePrepare);
+        let commit = mock_pbft_message(PbftMessageType::Commit);
+
+        assert!(apply_fault(FaultBehavior::DropPrePrepare, pre_prepare.clone()).is_empty());
+        assert_eq!(
+            vec![commit.clone()],
+            apply_fault(FaultBehavior::DropPrePrepare, commit.clone())
+        );
+
+        let equivocated = apply_fault(FaultBehavior::Equivocate, pre_prepare.clone());
+        assert_eq!(2, equivocated.len());
+        assert_eq!(pre_prepare, equivocated[0]);
+        assert_ne!(pre_prepare.get_block_id(), equivocated[1].get_block_id());
+
+        assert_eq!(
+            vec![commit.clone()],
+            apply_fault(FaultBehavior::DelayCommit(1), commit.clone())
+        );
+
+        let wrong = apply_fault(FaultBehavior::WrongSeqNum, commit);
+        assert_eq!(2, wrong[0].get_info().get_seq_num());
+    }
+}
@@ -20,20 +20,46 @@ use openssl::sha::Sha512;
 
 use crate::error::PbftError;
 
+/// Computes a digest of some bytes
+pub trait Digester {
+    fn digest(&self, data: &[u8]) -> Vec<u8>;
+}
+
+/// Computes SHA-512 digests; this is the hash the validator uses for the content of peer
+/// messages, so it must be used to verify the votes in consensus seals
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Sha512Digester;
+
+impl Digester for Sha512Digester {
+    fn digest(&self, data: &[u8]) -> Vec<u8> {
+        let mut sha = Sha512::new();
+        sha.update(data);
+        let mut bytes = Vec::new();
+        bytes.extend(sha.finish().iter());
+        bytes
+    }
+}
+
 /// Hashes the given bytes with SHA-512
 pub fn hash_sha512(bytes: &[u8]) -> Vec<u8> {
-    let mut sha = Sha512::new();
-    sha.update(bytes);
-    let mut bytes = Vec::new();
-    bytes.extend(sha.finish().iter());
-    bytes
+    Sha512Digester.digest(bytes)
 }
 
 /// Verifies that the SHA-512 hash of the given content matches the given hash
 pub fn verify_sha512(content: &[u8], content_hash: &[u8]) -> Result<(), PbftError> {
-    let computed_sha512 = hash_sha512(content);
+    verify_digest(&Sha512Digester, content, content_hash)
+}
 
-    if computed_sha512 != content_hash {
+/// Verifies that the digest of the given content (computed by the `digester`) matches the given
+/// hash
+pub fn verify_digest(
+    digester: &dyn Digester,
+    content: &[u8],
+    content_hash: &[u8],
+) -> Result<(), PbftError> {
+    let computed_hash = digester.digest(content);
+
+    if computed_hash != content_hash {
         Err(PbftError::SigningError(format!(
             "Hash verification failed - Content: `{:?}`, Hash: `{:?}`",
             content, content_hash
@@ -71,4 +97,26 @@ mod tests {
         assert!(verify_sha512(bytes, &correct_hash).is_ok());
         assert!(verify_sha512(bytes, &incorrect_hash).is_err());
     }
+
+    /// Hashing is done through the `Digester` trait, so a different hash function can be used
+    /// where the protocol doesn't dictate one (SHA-512 must still be used to verify the content
+    /// of peer messages, since that is what the validator uses).
+    ///
+    /// This test verifies that `verify_digest` uses the provided digester, using a trivial
+    /// digester that reverses its input.
+    #[test]
+    fn test_alternate_digester() {
+        struct ReverseDigester;
+
+        impl Digester for ReverseDigester {
+            fn digest(&self, data: &[u8]) -> Vec<u8> {
+                data.iter().rev().cloned().collect()
+            }
+        }
+
+        assert!(verify_digest(&ReverseDigester, b"abc", b"cba").is_ok());
+        assert!(verify_digest(&ReverseDigester, b"abc", b"abc").is_err());
+        assert!(verify_digest(&Sha512Digester, b"abc", &hash_sha512(b"abc")).is_ok());
+        assert!(verify_digest(&Sha512Digester, b"abc", b"cba").is_err());
+    }
 }
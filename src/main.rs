@@ -36,14 +36,19 @@ use log4rs::encode::pattern::PatternEncoder;
 use sawtooth_sdk::consensus::zmq_driver::ZmqDriver;
 
 pub mod config;
+pub mod decision_log;
 pub mod engine;
 pub mod error;
+#[cfg(feature = "fault-injection")]
+pub mod fault;
 pub mod hash;
 pub mod message_extensions;
 pub mod message_log;
 pub mod message_type;
 pub mod node;
+pub mod observer;
 mod protos;
+pub mod replay;
 pub mod state;
 pub mod storage;
 #[cfg(test)]
@@ -103,6 +108,9 @@ fn main() {
     if let Some(max_log_size) = args.max_log_size {
         pbft_config.max_log_size = max_log_size;
     }
+    if let Some(log_verbosity) = args.log_verbosity {
+        pbft_config.log_verbosity = log_verbosity;
+    }
 
     let pbft_engine = engine::PbftEngine::new(pbft_config);
 
@@ -142,6 +150,9 @@ fn parse_args() -> PbftCliArgs {
          "increase output verbosity")
         (@arg logconfig: -L --("log-config") +takes_value
          "path to logging config file")
+        (@arg log_verbosity: --("log-verbosity") +takes_value
+         "how much per-message consensus logging to emit ('quiet', 'normal', or 'trace'; \
+          default 'normal')")
         (@arg exponential_retry_base: -b --("exponential-retry-base") +takes_value
          "base timeout for exponential backoff (default 100 ms)")
         (@arg exponential_retry_max: -m --("exponential-retry-max") +takes_value
@@ -190,6 +201,9 @@ fn parse_args() -> PbftCliArgs {
         .parse::<u64>()
         .ok();
     let storage_location = matches.value_of("storage_location").map(String::from);
+    let log_verbosity = matches
+        .value_of("log_verbosity")
+        .and_then(|verbosity| verbosity.parse().ok());
 
     PbftCliArgs {
         log_config,
@@ -200,6 +214,7 @@ fn parse_args() -> PbftCliArgs {
         update_recv_timeout,
         max_log_size,
         storage_location,
+        log_verbosity,
     }
 }
 
@@ -213,4 +228,5 @@ pub struct PbftCliArgs {
     update_recv_timeout: Option<u64>,
     max_log_size: Option<u64>,
     storage_location: Option<String>,
+    log_verbosity: Option<config::LogVerbosity>,
 }
@@ -60,6 +60,8 @@ impl Hash for PbftSeal {
         for vote in self.get_commit_votes() {
             vote.hash(state);
         }
+        self.get_published_view().hash(state);
+        self.get_published_seq_num().hash(state);
     }
 }
 
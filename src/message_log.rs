@@ -21,8 +21,9 @@
 
 use std::collections::{HashMap, HashSet};
 use std::fmt;
+use std::rc::Rc;
 
-use sawtooth_sdk::consensus::engine::{Block, BlockId};
+use sawtooth_sdk::consensus::engine::{Block, BlockId, PeerId};
 
 use crate::config::PbftConfig;
 use crate::message_type::{ParsedMessage, PbftMessageType};
@@ -38,7 +39,11 @@ pub struct PbftLog {
     blocks: HashSet<Block>,
 
     /// All messages accepted by the node that have not been garbage collected
-    messages: HashSet<ParsedMessage>,
+    messages: HashSet<Rc<ParsedMessage>>,
+
+    /// Index of the messages in the log by (type, view, sequence number); the messages are shared
+    /// with `messages`, so they aren't stored twice
+    index: HashMap<(PbftMessageType, u64, u64), Vec<Rc<ParsedMessage>>>,
 
     /// Maximum log size
     max_log_size: u64,
@@ -72,6 +77,7 @@ impl PbftLog {
             unvalidated_blocks: HashMap::new(),
             blocks: HashSet::new(),
             messages: HashSet::new(),
+            index: HashMap::new(),
             max_log_size: config.max_log_size,
         }
     }
@@ -128,7 +134,105 @@ impl PbftLog {
     /// Add a parsed PBFT message to the log
     pub fn add_message(&mut self, msg: ParsedMessage) {
         trace!("Adding message to log: {:?}", msg);
-        self.messages.insert(msg);
+        let msg = Rc::new(msg);
+        if self.messages.insert(Rc::clone(&msg)) {
+            let info = msg.info();
+            self.index
+                .entry((
+                    PbftMessageType::from(info.get_msg_type()),
+                    info.get_view(),
+                    info.get_seq_num(),
+                ))
+                .or_default()
+                .push(msg);
+        }
+    }
+
+    /// Add a `ViewChange` to the log, replacing any `ViewChange` for the same view from the same
+    /// signer, so the log holds at most one `ViewChange` per node for each view
+    pub fn add_view_change(&mut self, msg: ParsedMessage) {
+        let view = msg.info().get_view();
+        let signer_id = msg.info().get_signer_id().to_vec();
+        let is_replaced = |logged: &ParsedMessage| {
+            PbftMessageType::from(logged.info().get_msg_type()) == PbftMessageType::ViewChange
+                && logged.info().get_view() == view
+                && logged.info().get_signer_id() == signer_id.as_slice()
+        };
+
+        self.messages.retain(|logged| !is_replaced(logged));
+        for ((msg_type, msg_view, _), msgs) in self.index.iter_mut() {
+            if *msg_type == PbftMessageType::ViewChange && *msg_view == view {
+                msgs.retain(|logged| !is_replaced(logged));
+            }
+        }
+        self.index.retain(|_, msgs| !msgs.is_empty());
+
+        self.add_message(msg);
+    }
+
+    /// Add a `Prepare` or `Commit` to the log, replacing any earlier copy of the same vote (same
+    /// type, view, sequence number, block, and signer) that has a different nonce, so the log
+    /// holds at most one copy of each node's vote
+    pub fn add_vote(&mut self, msg: ParsedMessage) {
+        let info = msg.info();
+        let key = (
+            PbftMessageType::from(info.get_msg_type()),
+            info.get_view(),
+            info.get_seq_num(),
+        );
+        let is_replaced = |logged: &ParsedMessage| {
+            logged.info().get_signer_id() == info.get_signer_id()
+                && logged.get_block_id() == msg.get_block_id()
+        };
+
+        if let Some(msgs) = self.index.get_mut(&key) {
+            for logged in msgs.iter().filter(|logged| is_replaced(logged)) {
+                self.messages.remove(logged);
+            }
+            msgs.retain(|logged| !is_replaced(logged));
+        }
+
+        self.add_message(msg);
+    }
+
+    /// Check if the log already has the given message
+    pub fn contains(&self, msg: &ParsedMessage) -> bool {
+        self.messages.contains(msg)
+    }
+
+    /// Get the number of messages of each type in the log
+    pub fn count_by_type(&self) -> HashMap<PbftMessageType, usize> {
+        let mut counts = HashMap::new();
+        for ((msg_type, _, _), msgs) in &self.index {
+            *counts.entry(*msg_type).or_insert(0) += msgs.len();
+        }
+        counts
+    }
+
+    /// Get the number of blocks in the log (validated and waiting to be validated)
+    pub fn block_count(&self) -> usize {
+        self.blocks.len() + self.unvalidated_blocks.len()
+    }
+
+    /// Get an estimate of how much memory the log's contents take up, in bytes: the serialized
+    /// size of each message (with its header and signature) plus the payload and summary of each
+    /// block. Bookkeeping overhead, such as the index (which shares the messages rather than
+    /// copying them), is not included.
+    pub fn approximate_size_bytes(&self) -> usize {
+        let messages_size: usize = self
+            .messages
+            .iter()
+            .map(|msg| {
+                msg.header_bytes.len() + msg.header_signature.len() + msg.message_bytes.len()
+            })
+            .sum();
+        let blocks_size: usize = self
+            .blocks
+            .iter()
+            .chain(self.unvalidated_blocks.values())
+            .map(|block| block.payload.len() + block.summary.len())
+            .sum();
+        messages_size + blocks_size
     }
 
     /// Check if the log has a PrePrepare at the given view and sequence number that matches the
@@ -145,13 +249,10 @@ impl PbftLog {
         msg_type: PbftMessageType,
         sequence_number: u64,
     ) -> Vec<&ParsedMessage> {
-        self.messages
+        self.index
             .iter()
-            .filter(|&msg| {
-                let info = (*msg).info();
-                info.get_msg_type() == String::from(msg_type)
-                    && info.get_seq_num() == sequence_number
-            })
+            .filter(|((typ, _, seq), _)| *typ == msg_type && *seq == sequence_number)
+            .flat_map(|(_, msgs)| msgs.iter().map(Rc::as_ref))
             .collect()
     }
 
@@ -161,12 +262,10 @@ impl PbftLog {
         msg_type: PbftMessageType,
         view: u64,
     ) -> Vec<&ParsedMessage> {
-        self.messages
+        self.index
             .iter()
-            .filter(|&msg| {
-                let info = (*msg).info();
-                info.get_msg_type() == String::from(msg_type) && info.get_view() == view
-            })
+            .filter(|((typ, v, _), _)| *typ == msg_type && *v == view)
+            .flat_map(|(_, msgs)| msgs.iter().map(Rc::as_ref))
             .collect()
     }
 
@@ -177,15 +276,10 @@ impl PbftLog {
         sequence_number: u64,
         view: u64,
     ) -> Vec<&ParsedMessage> {
-        self.messages
-            .iter()
-            .filter(|&msg| {
-                let info = (*msg).info();
-                info.get_msg_type() == String::from(msg_type)
-                    && info.get_seq_num() == sequence_number
-                    && info.get_view() == view
-            })
-            .collect()
+        self.index
+            .get(&(msg_type, view, sequence_number))
+            .map(|msgs| msgs.iter().map(Rc::as_ref).collect())
+            .unwrap_or_default()
     }
 
     /// Obtain all messages from the log that match the given type, sequence number, view, and
@@ -197,19 +291,117 @@ impl PbftLog {
         view: u64,
         block_id: &[u8],
     ) -> Vec<&ParsedMessage> {
-        self.messages
-            .iter()
-            .filter(|&msg| {
-                let info = (*msg).info();
-                let msg_block_id = (*msg).get_block_id();
-                info.get_msg_type() == String::from(msg_type)
-                    && info.get_seq_num() == sequence_number
-                    && info.get_view() == view
-                    && msg_block_id == block_id
-            })
+        self.get_messages_of_type_seq_view(msg_type, sequence_number, view)
+            .into_iter()
+            .filter(|msg| msg.get_block_id() == block_id)
             .collect()
     }
 
+    /// Obtain the IDs of the nodes that signed messages in the log that match the given type,
+    /// sequence number, view, and block_id; the IDs are sorted and deduplicated
+    pub fn get_signers_of_type_seq_view_block(
+        &self,
+        msg_type: PbftMessageType,
+        sequence_number: u64,
+        view: u64,
+        block_id: &[u8],
+    ) -> Vec<PeerId> {
+        let mut signers = self
+            .get_messages_of_type_seq_view_block(msg_type, sequence_number, view, block_id)
+            .iter()
+            .map(|msg| msg.info().get_signer_id().to_vec())
+            .collect::<Vec<_>>();
+        signers.sort();
+        signers.dedup();
+        signers
+    }
+
+    /// Obtain the IDs of the nodes whose votes formed a quorum of messages in the log that match
+    /// the given type, sequence number, view, and block_id: the signers of the matching messages,
+    /// in the order the messages were added to the log, up to the first one that brings them to at
+    /// least `required` votes (where `votes` gives the number of votes of the nodes that signed
+    /// them). The IDs are sorted, and matching messages that were added after the quorum was
+    /// reached aren't included.
+    pub fn get_quorum_signers<F>(
+        &self,
+        msg_type: PbftMessageType,
+        sequence_number: u64,
+        view: u64,
+        block_id: &[u8],
+        required: u64,
+        votes: F,
+    ) -> Vec<PeerId>
+    where
+        F: Fn(&[PeerId]) -> u64,
+    {
+        let mut signers: Vec<PeerId> = Vec::new();
+        for msg in
+            self.get_messages_of_type_seq_view_block(msg_type, sequence_number, view, block_id)
+        {
+            let signer_id = msg.info().get_signer_id();
+            if signers.iter().any(|id| id.as_slice() == signer_id) {
+                continue;
+            }
+            signers.push(signer_id.to_vec());
+            if votes(&signers) >= required {
+                break;
+            }
+        }
+        signers.sort();
+        signers
+    }
+
+    /// Check if the `prepared` predicate holds for the given view, sequence number, and block:
+    /// the log has a matching `PrePrepare` and matching `Prepare`s with at least `required` votes,
+    /// where `votes` gives the number of votes of the nodes that signed them. Prepares are counted
+    /// by signer, so a node that sends the same `Prepare` more than once (with different nonces)
+    /// is only counted once.
+    pub fn prepared<F>(
+        &self,
+        view: u64,
+        seq_num: u64,
+        block_id: &[u8],
+        required: u64,
+        votes: F,
+    ) -> bool
+    where
+        F: Fn(&[PeerId]) -> u64,
+    {
+        self.has_pre_prepare(seq_num, view, block_id)
+            && votes(&self.get_signers_of_type_seq_view_block(
+                PbftMessageType::Prepare,
+                seq_num,
+                view,
+                block_id,
+            )) >= required
+    }
+
+    /// Remove the `PrePrepare`s, `Prepare`s, and `Commit`s from views earlier than `view` with a
+    /// sequence number of at least `seq_num`. These are votes for blocks that haven't been
+    /// committed, which can't be used once the view has changed; votes for blocks that were
+    /// already committed are kept, since they may be needed to build a consensus seal.
+    pub fn clear_views_before(&mut self, view: u64, seq_num: u64) {
+        let is_stale = |msg_type: PbftMessageType, msg_view: u64, msg_seq_num: u64| {
+            msg_view < view
+                && msg_seq_num >= seq_num
+                && (msg_type == PbftMessageType::PrePrepare
+                    || msg_type == PbftMessageType::Prepare
+                    || msg_type == PbftMessageType::Commit)
+        };
+
+        self.messages.retain(|msg| {
+            let info = msg.info();
+            !is_stale(
+                PbftMessageType::from(info.get_msg_type()),
+                info.get_view(),
+                info.get_seq_num(),
+            )
+        });
+        self.index.retain(|(msg_type, msg_view, msg_seq_num), _| {
+            !is_stale(*msg_type, *msg_view, *msg_seq_num)
+        });
+    }
+
     /// Garbage collect the log if it has reached the `max_log_size`
     #[allow(clippy::ptr_arg)]
     pub fn garbage_collect(&mut self, current_seq_num: u64) {
@@ -219,9 +411,16 @@ impl PbftLog {
             // needs to build the next consensus seal
             self.messages
                 .retain(|msg| msg.info().get_seq_num() >= current_seq_num - 1);
+            self.index
+                .retain(|(_, _, seq_num), _| *seq_num >= current_seq_num - 1);
 
             self.blocks
                 .retain(|block| block.block_num >= current_seq_num - 1);
+
+            // Blocks that are still waiting to be validated are pruned the same way; they could
+            // never be committed, so there is no reason to keep them around
+            self.unvalidated_blocks
+                .retain(|_, block| block.block_num >= current_seq_num - 1);
         }
     }
 
@@ -419,4 +618,247 @@ mod tests {
         assert_eq!(1, res10.len());
         assert!(res10.contains(&&msg9));
     }
+
+    /// The `prepared` predicate requires a matching `PrePrepare` in addition to the `Prepare`s,
+    /// and it counts the `Prepare`s by signer.
+    ///
+    /// This test verifies that `prepared` is false when there are 3 matching Prepares but no
+    /// PrePrepare, and when there is a PrePrepare but one of the Prepares is a resend (with a new
+    /// nonce) of another node's Prepare; it becomes true once a third node's Prepare is added.
+    #[test]
+    fn test_prepared() {
+        let count = |signers: &[PeerId]| signers.len() as u64;
+        let cfg = mock_config(4);
+        let mut log = PbftLog::new(&cfg);
+
+        for signer in 0..3 {
+            log.add_message(mock_msg(
+                PbftMessageType::Prepare,
+                0,
+                1,
+                vec![signer],
+                vec![1],
+                false,
+            ));
+        }
+        assert!(!log.prepared(0, 1, &[1], 3, count));
+
+        let mut log = PbftLog::new(&cfg);
+        log.add_message(mock_msg(
+            PbftMessageType::PrePrepare,
+            0,
+            1,
+            vec![0],
+            vec![1],
+            false,
+        ));
+        log.add_message(mock_msg_with_nonce(
+            PbftMessageType::Prepare,
+            0,
+            1,
+            vec![0],
+            vec![1],
+            1,
+        ));
+        log.add_message(mock_msg_with_nonce(
+            PbftMessageType::Prepare,
+            0,
+            1,
+            vec![1],
+            vec![1],
+            1,
+        ));
+        log.add_message(mock_msg_with_nonce(
+            PbftMessageType::Prepare,
+            0,
+            1,
+            vec![1],
+            vec![1],
+            2,
+        ));
+        assert!(!log.prepared(0, 1, &[1], 3, count));
+
+        log.add_message(mock_msg(
+            PbftMessageType::Prepare,
+            0,
+            1,
+            vec![2],
+            vec![1],
+            false,
+        ));
+        assert!(log.prepared(0, 1, &[1], 3, count));
+        assert!(!log.prepared(0, 1, &[2], 3, count));
+    }
+
+    /// `approximate_size_bytes` estimates the memory used by the log from the serialized sizes of
+    /// its messages and blocks, so operators can see how large the log gets.
+    ///
+    /// This test verifies that the estimate starts at zero, grows as messages and blocks are
+    /// added (and doesn't change when a duplicate message is added), and shrinks after the log is
+    /// garbage collected.
+    #[test]
+    fn test_approximate_size_bytes() {
+        let cfg = mock_config(4);
+        let mut log = PbftLog::new(&cfg);
+        log.set_max_log_size(4);
+        assert_eq!(0, log.approximate_size_bytes());
+
+        let mut last_size = 0;
+        for seq_num in 1..5 {
+            let msg = mock_msg(
+                PbftMessageType::Commit,
+                0,
+                seq_num,
+                vec![1],
+                vec![seq_num as u8],
+                false,
+            );
+            log.add_message(msg.clone());
+            assert!(log.approximate_size_bytes() > last_size);
+            last_size = log.approximate_size_bytes();
+
+            log.add_message(msg);
+            assert_eq!(last_size, log.approximate_size_bytes());
+        }
+
+        let mut block = mock_block(4);
+        block.payload = vec![0; 100];
+        log.add_validated_block(block);
+        assert_eq!(last_size + 100, log.approximate_size_bytes());
+        last_size = log.approximate_size_bytes();
+
+        log.garbage_collect(5);
+        assert!(log.approximate_size_bytes() < last_size);
+        assert!(log.approximate_size_bytes() > 0);
+    }
+
+    /// When the log is garbage collected, blocks that are older than the previous sequence number
+    /// are dropped, since they will never be committed. This applies to blocks that are still
+    /// waiting to be validated as well as to validated blocks; otherwise, blocks that were never
+    /// validated would stay in the log forever.
+    ///
+    /// This test adds validated and unvalidated blocks on both sides of the garbage collection
+    /// boundary and verifies that only the blocks at or above the boundary are kept.
+    #[test]
+    fn test_block_garbage_collection() {
+        let cfg = mock_config(4);
+        let mut log = PbftLog::new(&cfg);
+        log.set_max_log_size(1);
+        log.add_message(mock_msg(
+            PbftMessageType::Commit,
+            0,
+            4,
+            vec![1],
+            vec![4],
+            false,
+        ));
+
+        for num in 1..3 {
+            log.add_validated_block(mock_block(num));
+        }
+        for num in 3..7 {
+            log.add_unvalidated_block(mock_block(num));
+        }
+        log.add_validated_block(mock_block(3));
+
+        // Garbage collect at seq_num 5; the blocks for seq_num 4 and up should be kept
+        log.garbage_collect(5);
+
+        assert!(log.get_block_with_id(&[1]).is_none());
+        assert!(log.get_block_with_id(&[2]).is_none());
+        assert!(log.get_block_with_id(&[3]).is_none());
+        assert!(log.get_unvalidated_block_with_id(&[3]).is_none());
+        for num in 4..7 {
+            assert!(log.get_unvalidated_block_with_id(&[num]).is_some());
+        }
+    }
+
+    /// Messages in the log are indexed by their type, view, and sequence number so that they can
+    /// be retrieved without scanning the whole log. This test populates a log with a large number
+    /// of messages, garbage collects some of them, and verifies that the results of all of the
+    /// message retrieval methods match the results of a naive scan over every message in the log,
+    /// and that the index doesn't hold copies of the messages.
+    #[test]
+    fn test_message_index() {
+        // Initialize an empty log and add a large number of messages to it
+        let cfg = mock_config(4);
+        let mut log = PbftLog::new(&cfg);
+        log.set_max_log_size(1);
+        let types = [
+            PbftMessageType::PrePrepare,
+            PbftMessageType::Prepare,
+            PbftMessageType::Commit,
+            PbftMessageType::ViewChange,
+        ];
+        for msg_type in types.iter() {
+            for view in 0..5 {
+                for seq_num in 0..20 {
+                    for signer in 0..4 {
+                        log.add_message(mock_msg(
+                            *msg_type,
+                            view,
+                            seq_num,
+                            vec![signer],
+                            vec![(seq_num % 3) as u8],
+                            false,
+                        ));
+                    }
+                }
+            }
+        }
+        log.garbage_collect(5);
+
+        fn sorted(mut msgs: Vec<&ParsedMessage>) -> Vec<&ParsedMessage> {
+            msgs.sort_by_key(|msg| msg.message_bytes.clone());
+            msgs
+        }
+        let naive_scan = |filter: &dyn Fn(&ParsedMessage) -> bool| {
+            sorted(
+                log.messages
+                    .iter()
+                    .map(Rc::as_ref)
+                    .filter(|msg| filter(msg))
+                    .collect(),
+            )
+        };
+
+        // Verify the results of each retrieval method match a naive scan of all messages
+        for msg_type in types.iter() {
+            let is_type =
+                |msg: &ParsedMessage| PbftMessageType::from(msg.info().get_msg_type()) == *msg_type;
+            for view in 0..6 {
+                assert_eq!(
+                    naive_scan(&|msg| is_type(msg) && msg.info().get_view() == view),
+                    sorted(log.get_messages_of_type_view(*msg_type, view)),
+                );
+            }
+            for seq_num in 0..21 {
+                assert_eq!(
+                    naive_scan(&|msg| is_type(msg) && msg.info().get_seq_num() == seq_num),
+                    sorted(log.get_messages_of_type_seq(*msg_type, seq_num)),
+                );
+                for view in 0..6 {
+                    let block_id = vec![(seq_num % 3) as u8];
+                    assert_eq!(
+                        naive_scan(&|msg| is_type(msg)
+                            && msg.info().get_seq_num() == seq_num
+                            && msg.info().get_view() == view),
+                        sorted(log.get_messages_of_type_seq_view(*msg_type, seq_num, view)),
+                    );
+                    assert_eq!(
+                        naive_scan(&|msg| is_type(msg)
+                            && msg.info().get_seq_num() == seq_num
+                            && msg.info().get_view() == view
+                            && msg.get_block_id() == block_id),
+                        sorted(log.get_messages_of_type_seq_view_block(
+                            *msg_type, seq_num, view, &block_id
+                        )),
+                    );
+                }
+            }
+        }
+
+        // The index shares the messages in the log rather than holding its own copies
+        assert!(log.messages.iter().all(|msg| Rc::strong_count(msg) == 2));
+    }
 }
@@ -230,7 +230,7 @@ impl ParsedMessage {
 }
 
 // Messages related to PBFT consensus
-#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd)]
+#[derive(Clone, Copy, Debug, Hash, PartialEq, Eq, PartialOrd)]
 pub enum PbftMessageType {
     /// Basic message types for the multicast protocol
     PrePrepare,
@@ -242,6 +242,7 @@ pub enum PbftMessageType {
     ViewChange,
     SealRequest,
     Seal,
+    Heartbeat,
 
     Unset,
 }
@@ -256,6 +257,7 @@ impl fmt::Display for PbftMessageType {
             PbftMessageType::ViewChange => "VC",
             PbftMessageType::SealRequest => "Rq",
             PbftMessageType::Seal => "Rs",
+            PbftMessageType::Heartbeat => "Hb",
             PbftMessageType::Unset => "Un",
         };
         write!(f, "{}", txt)
@@ -272,6 +274,7 @@ impl<'a> From<&'a str> for PbftMessageType {
             "ViewChange" => PbftMessageType::ViewChange,
             "SealRequest" => PbftMessageType::SealRequest,
             "Seal" => PbftMessageType::Seal,
+            "Heartbeat" => PbftMessageType::Heartbeat,
             _ => {
                 warn!("Unhandled PBFT message type: {}", s);
                 PbftMessageType::Unset
@@ -0,0 +1,33 @@
+/*
+ * Copyright 2018 Bitwise IO, Inc.
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ * ------------------------------------------------------------------------------
+ */
+
+//! Interface for external monitors to be notified of consensus events
+
+use sawtooth_sdk::consensus::engine::BlockId;
+
+/// Receives notifications of consensus events from a `PbftNode`, so external tooling can react to
+/// them without parsing the logs
+///
+/// All methods have empty default implementations, so an observer only needs to implement the
+/// ones it is interested in.
+pub trait PbftObserver {
+    /// Called when the node has committed the block with the given ID and sequence number
+    fn on_commit(&mut self, _block_id: &BlockId, _seq_num: u64) {}
+
+    /// Called when the node has moved from the old view to the new view
+    fn on_view_change(&mut self, _old_view: u64, _new_view: u64) {}
+}
@@ -0,0 +1,129 @@
+/*
+ * Copyright 2018 Bitwise IO, Inc.
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ * ------------------------------------------------------------------------------
+ */
+
+//! Recording of the inputs a node receives, so they can be replayed into a fresh node to
+//! reproduce a consensus bug
+//!
+//! Only the inputs are recorded; timeouts, batching, and anything else that depends on the clock
+//! aren't, so replaying is best-effort (see `PbftNode::replay`).
+//!
+//! A recording has one JSON object per line; each object has a `type` field (`PeerMessage`,
+//! `BlockNew`, `BlockValid`, `BlockInvalid`, or `BlockCommit`) and the input's contents, with all
+//! bytes hex-encoded.
+
+use sawtooth_sdk::consensus::engine::{Block, BlockId, PeerMessage};
+
+use crate::error::PbftError;
+use crate::message_type::ParsedMessage;
+
+/// An input to a `PbftNode` that can be recorded and replayed
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type")]
+pub enum RecordedInput {
+    /// A message from a peer; the message type and bytes are what the `PeerMessage` carried
+    PeerMessage {
+        message_type: String,
+        header_bytes: String,
+        header_signature: String,
+        content: String,
+    },
+    /// A new block from the validator
+    BlockNew {
+        block_id: String,
+        previous_id: String,
+        signer_id: String,
+        block_num: u64,
+        payload: String,
+        summary: String,
+    },
+    /// The validator found the block to be valid
+    BlockValid { block_id: String },
+    /// The validator found the block to be invalid
+    BlockInvalid { block_id: String },
+    /// The validator committed the block
+    BlockCommit { block_id: String },
+}
+
+impl RecordedInput {
+    /// Create a recording of a message from a peer
+    pub fn peer_message(msg: &ParsedMessage) -> Self {
+        RecordedInput::PeerMessage {
+            message_type: msg.info().get_msg_type().into(),
+            header_bytes: hex::encode(&msg.header_bytes),
+            header_signature: hex::encode(&msg.header_signature),
+            content: hex::encode(&msg.message_bytes),
+        }
+    }
+
+    /// Create a recording of a new block
+    pub fn block_new(block: &Block) -> Self {
+        RecordedInput::BlockNew {
+            block_id: hex::encode(&block.block_id),
+            previous_id: hex::encode(&block.previous_id),
+            signer_id: hex::encode(&block.signer_id),
+            block_num: block.block_num,
+            payload: hex::encode(&block.payload),
+            summary: hex::encode(&block.summary),
+        }
+    }
+
+    /// Parse a recorded message from a peer back into a `ParsedMessage` for the node with the
+    /// given ID
+    pub fn to_parsed_message(
+        message_type: &str,
+        header_bytes: &str,
+        header_signature: &str,
+        content: &str,
+        own_id: &[u8],
+    ) -> Result<ParsedMessage, PbftError> {
+        let mut peer_message = PeerMessage::default();
+        peer_message.header.message_type = message_type.into();
+        peer_message.header_bytes = decode(header_bytes)?;
+        peer_message.header_signature = decode(header_signature)?;
+        peer_message.content = decode(content)?;
+        ParsedMessage::from_peer_message(peer_message, own_id)
+    }
+
+    /// Parse a recorded block back into a `Block`
+    pub fn to_block(
+        block_id: &str,
+        previous_id: &str,
+        signer_id: &str,
+        block_num: u64,
+        payload: &str,
+        summary: &str,
+    ) -> Result<Block, PbftError> {
+        Ok(Block {
+            block_id: decode(block_id)?,
+            previous_id: decode(previous_id)?,
+            signer_id: decode(signer_id)?,
+            block_num,
+            payload: decode(payload)?,
+            summary: decode(summary)?,
+        })
+    }
+}
+
+/// Decode the hex-encoded bytes (such as a block ID) of a recorded input
+pub fn decode(bytes: &str) -> Result<BlockId, PbftError> {
+    hex::decode(bytes).map_err(|err| {
+        PbftError::InternalError(format!(
+            "Invalid bytes in recorded input ({}): {}",
+            bytes, err
+        ))
+    })
+}
@@ -17,14 +17,17 @@
 
 //! Information about a PBFT node's state
 
+use std::collections::HashMap;
 use std::fmt;
-use std::time::Duration;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 use sawtooth_sdk::consensus::engine::{BlockId, PeerId};
 
-use crate::config::PbftConfig;
+use crate::config::{
+    total_vote_weight, vote_weight, PbftConfig, PbftThresholds, PrimarySelection, QuorumSize,
+};
 use crate::error::PbftError;
-use crate::timing::Timeout;
+use crate::timing::TimerManager;
 
 /// Phases of the PBFT algorithm, in `Normal` mode
 #[derive(Debug, PartialEq, Eq, PartialOrd, Clone, Serialize, Deserialize)]
@@ -80,6 +83,14 @@ impl fmt::Display for PbftState {
     }
 }
 
+/// Increment a view or sequence number (named by `name` for the error message); returns an error
+/// instead of overflowing if the number is already `u64::MAX`
+pub fn checked_increment(value: u64, name: &str) -> Result<u64, PbftError> {
+    value
+        .checked_add(1)
+        .ok_or_else(|| PbftError::InternalError(format!("Can't increment {} past {}", name, value)))
+}
+
 /// Information about the PBFT algorithm's state
 #[derive(Debug, Serialize, Deserialize)]
 pub struct PbftState {
@@ -107,18 +118,32 @@ pub struct PbftState {
     /// The maximum number of faulty nodes in the network
     pub f: u64,
 
-    /// Timer used to make sure the primary publishes blocks in a timely manner. If not, then this
-    /// node will initiate a view change.
-    pub idle_timeout: Timeout,
-
-    /// Timer used to make sure the network doesn't get stuck if it fails to commit a block in a
-    /// reasonable amount of time. If it doesn't commit a block in time, this node will initiate a
-    /// view change when the timer expires.
-    pub commit_timeout: Timeout,
-
-    /// When view changing, timer is used to make sure a valid NewView message is sent by the new
-    /// primary in a timely manner. If not, this node will start a different view change.
-    pub view_change_timeout: Timeout,
+    /// How the primary is chosen for each view; not persisted, since it must match the on-chain
+    /// setting, which is loaded on start-up and read again each time a block is committed
+    #[serde(skip)]
+    pub primary_selection: PrimarySelection,
+
+    /// How many matching messages are needed for each kind of quorum; not persisted, for the same
+    /// reason as the primary selection strategy
+    #[serde(skip)]
+    pub thresholds: PbftThresholds,
+
+    /// The number of votes of each member that doesn't have exactly one; not persisted, for the
+    /// same reason as the primary selection strategy
+    #[serde(skip)]
+    pub vote_weights: HashMap<PeerId, u32>,
+
+    /// Timers used to detect a faulty primary or a stalled network:
+    /// + The idle timer makes sure the primary publishes blocks in a timely manner. If not, then
+    ///   this node will initiate a view change.
+    /// + The commit timer makes sure the network doesn't get stuck if it fails to commit a block
+    ///   in a reasonable amount of time. If it doesn't commit a block in time, this node will
+    ///   initiate a view change when the timer expires.
+    /// + When view changing, the view change timer is used to make sure a valid NewView message
+    ///   is sent by the new primary in a timely manner. If not, this node will start a different
+    ///   view change.
+    #[serde(flatten)]
+    pub timers: TimerManager,
 
     /// The duration of the view change timeout; when a view change is initiated for view v + 1,
     /// the timeout will be equal to the `view_change_duration`; if the timeout expires and the
@@ -133,6 +158,26 @@ pub struct PbftState {
 
     /// How many blocks to commit before forcing a view change for fairness
     pub forced_view_change_interval: u64,
+
+    /// The nonce of the last message this node sent; other nodes only compare the nonces of
+    /// messages with the same type, view, and sequence number
+    #[serde(default, alias = "last_view_change_nonce")]
+    pub last_nonce: u64,
+
+    /// Whether this node has initialized a block that hasn't been finalized or cancelled yet
+    #[serde(default)]
+    pub block_initialized: bool,
+
+    /// The IDs of the nodes whose votes formed the last Prepare or Commit quorum this node
+    /// reached, sorted; comparing this with the list of members shows which nodes didn't vote
+    #[serde(default)]
+    pub last_quorum: Vec<PeerId>,
+
+    /// The client requests this node has evidence of that haven't been included in a committed
+    /// block yet, keyed by the hex-encoded request ID, along with the number of blocks that have
+    /// been committed since the request was tracked
+    #[serde(default)]
+    pub pending_requests: HashMap<String, u64>,
 }
 
 impl PbftState {
@@ -148,6 +193,11 @@ impl PbftState {
             panic!("This network does not contain enough nodes to be fault tolerant");
         }
 
+        // Seed the timeout jitter with this node's ID, so each node's timeouts are different
+        let jitter_seed = id
+            .iter()
+            .fold(0u64, |seed, byte| seed.rotate_left(8) ^ u64::from(*byte));
+
         PbftState {
             id,
             seq_num: head_block_num + 1,
@@ -157,26 +207,103 @@ impl PbftState {
             mode: PbftMode::Normal,
             f,
             member_ids: config.members.clone(),
-            idle_timeout: Timeout::new(config.idle_timeout),
-            commit_timeout: Timeout::new(config.commit_timeout),
-            view_change_timeout: Timeout::new(config.view_change_duration),
+            primary_selection: config.primary_selection.clone(),
+            thresholds: config.thresholds.clone(),
+            vote_weights: config.vote_weights.clone(),
+            timers: TimerManager::new(
+                config.idle_timeout,
+                config.commit_timeout,
+                config.view_change_duration,
+            )
+            .with_jitter(config.timeout_jitter, jitter_seed),
             view_change_duration: config.view_change_duration,
             exponential_retry_base: config.exponential_retry_base,
             exponential_retry_max: config.exponential_retry_max,
             forced_view_change_interval: config.forced_view_change_interval,
+            last_nonce: 0,
+            block_initialized: false,
+            last_quorum: Vec::new(),
+            pending_requests: HashMap::new(),
+        }
+    }
+
+    /// Update the list of members in the network and recompute `f`; since the primary is derived
+    /// from the list of members, this node's primary status may change as a result
+    ///
+    /// If the new list of members does not contain enough nodes to be Byzantine fault tolerant,
+    /// an error is returned and the membership is left unchanged.
+    pub fn update_members(&mut self, members: Vec<PeerId>) -> Result<(), PbftError> {
+        let f = (members.len().saturating_sub(1) / 3) as u64;
+        if f == 0 {
+            return Err(PbftError::InternalError(format!(
+                "A network of {} nodes does not contain enough nodes to be fault tolerant",
+                members.len()
+            )));
+        }
+
+        let was_primary = self.is_primary();
+        self.member_ids = members;
+        self.f = f;
+        self.log_primary_change(was_primary, "membership change");
+
+        Ok(())
+    }
+
+    /// Update how the primary is chosen for each view; this node's primary status may change as a
+    /// result
+    pub fn update_primary_selection(&mut self, primary_selection: PrimarySelection) {
+        let was_primary = self.is_primary();
+        self.primary_selection = primary_selection;
+        self.log_primary_change(was_primary, "primary selection change");
+    }
+
+    /// Log if this node became or stopped being the primary because of the given change
+    fn log_primary_change(&self, was_primary: bool, change: &str) {
+        match (was_primary, self.is_primary()) {
+            (false, true) => info!("{}: Became primary due to {}", self, change),
+            (true, false) => info!("{}: No longer primary due to {}", self, change),
+            _ => {}
+        }
+    }
+
+    /// Get the number of votes the given node has (1 unless a vote weight is configured for it)
+    pub fn vote_weight(&self, id: &[u8]) -> u64 {
+        vote_weight(&self.vote_weights, id)
+    }
+
+    /// Get the number of votes the given nodes have together; each node is counted once, even if
+    /// it appears more than once
+    pub fn total_vote_weight<'a, I: IntoIterator<Item = &'a [u8]>>(&self, ids: I) -> u64 {
+        total_vote_weight(&self.vote_weights, ids)
+    }
+
+    /// Get the number of votes needed for a quorum of the given size
+    ///
+    /// Without vote weights this is the quorum size for `f`; with vote weights, `f` is replaced
+    /// by the largest total weight of faulty members that the network can tolerate.
+    pub fn quorum_votes(&self, size: QuorumSize) -> u64 {
+        if self.vote_weights.is_empty() {
+            return size.size(self.f);
         }
+        size.votes(self.total_vote_weight(self.member_ids.iter().map(Vec::as_slice)))
+    }
+
+    /// Get the view after the current one; returns an error instead of overflowing if the node is
+    /// already on the last possible view
+    pub fn next_view(&self) -> Result<u64, PbftError> {
+        checked_increment(self.view, "view")
     }
 
     /// Obtain the ID for the primary node in the network
     pub fn get_primary_id(&self) -> PeerId {
-        let primary_index = (self.view as usize) % self.member_ids.len();
-        self.member_ids[primary_index].clone()
+        self.get_primary_id_at_view(self.view)
     }
 
     /// Obtain the ID for the primary node at the specified view
     pub fn get_primary_id_at_view(&self, view: u64) -> PeerId {
-        let primary_index = (view as usize) % self.member_ids.len();
-        self.member_ids[primary_index].clone()
+        self.primary_selection
+            .primary(&self.member_ids, view)
+            .clone()
     }
 
     /// Tell if this node is currently the primary
@@ -217,6 +344,20 @@ impl PbftState {
         }
     }
 
+    /// Get a new nonce for a message sent by this node
+    ///
+    /// The nonce is based on the current time (in milliseconds) so that it keeps increasing
+    /// across restarts, but it is always greater than the last nonce this node used, so a clock
+    /// that steps backwards can't make a re-sent message look like a replay.
+    pub fn next_nonce(&mut self) -> u64 {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|time| time.as_millis() as u64)
+            .unwrap_or(0);
+        self.last_nonce = now.max(self.last_nonce.saturating_add(1));
+        self.last_nonce
+    }
+
     pub fn at_forced_view_change(&self) -> bool {
         self.seq_num % self.forced_view_change_interval == 0
     }
@@ -226,6 +367,7 @@ impl PbftState {
 mod tests {
     use super::*;
     use crate::test_helpers::*;
+    use crate::timing::TimerName;
 
     /// This test will verify that calling `PbftState::new` will properly initialize a state struct
     /// and fail if there are not enough members.
@@ -241,11 +383,11 @@ mod tests {
         assert_eq!(PbftMode::Normal, state.mode);
         assert_eq!(cfg.members, state.member_ids);
         assert_eq!(1, state.f);
-        assert_eq!(cfg.idle_timeout, state.idle_timeout.duration());
-        assert_eq!(cfg.commit_timeout, state.commit_timeout.duration());
+        assert_eq!(cfg.idle_timeout, state.timers.duration(TimerName::Idle));
+        assert_eq!(cfg.commit_timeout, state.timers.duration(TimerName::Commit));
         assert_eq!(
             cfg.view_change_duration,
-            state.view_change_timeout.duration()
+            state.timers.duration(TimerName::ViewChange)
         );
         assert_eq!(cfg.view_change_duration, state.view_change_duration);
         assert_eq!(cfg.exponential_retry_base, state.exponential_retry_base);
@@ -260,6 +402,89 @@ mod tests {
         assert!(std::panic::catch_unwind(|| PbftState::new(vec![0], 0, &cfg)).is_err());
     }
 
+    /// When the list of members changes, `f` and the primary (which is derived from the list of
+    /// members and the current view) must be recomputed using the new list of members.
+    ///
+    /// This test verifies that `PbftState::update_members` properly updates `f` and the primary
+    /// when growing from 4 to 7 nodes and shrinking from 7 to 4 nodes, and that membership is left
+    /// unchanged if there would no longer be enough nodes to be fault tolerant.
+    #[test]
+    fn test_update_members() {
+        let mut state = PbftState::new(vec![1], 0, &mock_config(4));
+        state.view = 5;
+        assert_eq!(1, state.f);
+        assert_eq!(vec![1], state.get_primary_id());
+        assert!(state.is_primary());
+
+        // Grow from 4 to 7 nodes
+        let members_7 = (0..7).map(|id| vec![id]).collect::<Vec<_>>();
+        assert!(state.update_members(members_7.clone()).is_ok());
+        assert_eq!(members_7, state.member_ids);
+        assert_eq!(2, state.f);
+        assert_eq!(vec![5], state.get_primary_id());
+        assert!(!state.is_primary());
+
+        // Shrink from 7 to 4 nodes
+        let members_4 = mock_config(4).members;
+        assert!(state.update_members(members_4.clone()).is_ok());
+        assert_eq!(members_4, state.member_ids);
+        assert_eq!(1, state.f);
+        assert_eq!(vec![1], state.get_primary_id());
+        assert!(state.is_primary());
+
+        // Shrink to 3 nodes; membership should be unchanged
+        assert!(state.update_members(mock_config(3).members).is_err());
+        assert_eq!(members_4, state.member_ids);
+        assert_eq!(1, state.f);
+    }
+
+    /// State that was persisted by older versions of PBFT must still be loadable. Those versions
+    /// stored the timers as separate fields (which `TimerManager` keeps the names of), didn't
+    /// have any of the fields added since, and had an `Expired` timeout state for timers that had
+    /// expired.
+    ///
+    /// This test loads a state in that format with an expired idle timeout and verifies that it
+    /// is loaded with its values intact and with the idle timeout active and expired.
+    #[test]
+    fn test_load_older_state() {
+        let timeout = |state: &str, secs: u64| {
+            let mut timeout =
+                serde_json::to_value(crate::timing::Timeout::new(Duration::from_secs(secs)))
+                    .expect("Failed to serialize timeout");
+            let timeout = timeout.as_object_mut().expect("Timeout isn't an object");
+            timeout.remove("jitter");
+            timeout.insert("state".into(), state.into());
+            serde_json::Value::from(timeout.clone())
+        };
+        let older_state = serde_json::json!({
+            "id": [1],
+            "seq_num": 5,
+            "view": 2,
+            "chain_head": [4],
+            "phase": "PrePreparing",
+            "mode": "Normal",
+            "member_ids": [[0], [1], [2], [3]],
+            "f": 1,
+            "idle_timeout": timeout("Expired", 0),
+            "commit_timeout": timeout("Inactive", 10),
+            "view_change_timeout": timeout("Inactive", 5),
+            "view_change_duration": {"secs": 5, "nanos": 0},
+            "exponential_retry_base": {"secs": 0, "nanos": 100_000_000},
+            "exponential_retry_max": {"secs": 60, "nanos": 0},
+            "forced_view_change_interval": 100,
+        });
+
+        let state: PbftState =
+            serde_json::from_value(older_state).expect("Failed to load older state");
+        assert_eq!(5, state.seq_num);
+        assert_eq!(2, state.view);
+        assert_eq!(vec![4], state.chain_head);
+        assert_eq!(4, state.member_ids.len());
+        assert!(state.timers.is_active(TimerName::Idle));
+        assert_eq!(vec![TimerName::Idle], state.timers.expired_timers());
+        assert!(!state.timers.is_active(TimerName::Commit));
+    }
+
     /// Make sure that a normal PBFT cycle works properly
     /// `PrePreparing` => `Preparing` => `Committing` => `Finishing` => `PrePreparing`
     /// and that invalid phase changes are detected
@@ -53,6 +53,11 @@ pub trait Storage {
 
     fn read<'a>(&'a self) -> Box<dyn StorageReadGuard<'a, Self::S, Target = Self::S> + 'a>;
     fn write<'a>(&'a mut self) -> Box<dyn StorageWriteGuard<'a, Self::S, Target = Self::S> + 'a>;
+
+    /// Persist the object to the backing store now
+    fn flush(&mut self) {
+        drop(self.write());
+    }
 }
 
 /// Given a location string, returns the appropriate storage
@@ -17,8 +17,11 @@
 
 //! Helper functions used by unit tests
 
+use std::collections::HashMap;
+
 use crate::config::PbftConfig;
 use crate::message_type::{ParsedMessage, PbftMessageType};
+use crate::node::PbftNode;
 use crate::protos::pbft_message::{PbftMessage, PbftMessageInfo};
 use sawtooth_sdk::consensus::engine::{Block, BlockId, PeerId};
 
@@ -36,7 +39,7 @@ pub fn mock_block(num: u8) -> Block {
     Block {
         block_id: vec![num],
         previous_id,
-        signer_id: PeerId::from(vec![]),
+        signer_id: PeerId::from(vec![0]),
         block_num: num as u64,
         payload: vec![],
         summary: vec![],
@@ -61,3 +64,57 @@ pub fn mock_msg(
     parsed.from_self = from_self;
     parsed
 }
+
+/// Create a PbftMessage with the given nonce
+pub fn mock_msg_with_nonce(
+    msg_type: PbftMessageType,
+    view: u64,
+    seq_num: u64,
+    signer_id: PeerId,
+    block_id: BlockId,
+    nonce: u64,
+) -> ParsedMessage {
+    let mut info = PbftMessageInfo::new_from(msg_type, view, seq_num, signer_id);
+    info.set_nonce(nonce);
+    let mut msg = PbftMessage::new();
+    msg.set_info(info);
+    msg.set_block_id(block_id);
+
+    let mut parsed = ParsedMessage::from_pbft_message(msg).expect("Failed to parse PbftMessage");
+    parsed.from_self = false;
+    parsed
+}
+
+/// Create a ViewChange message with the given nonce
+pub fn mock_view_change(
+    view: u64,
+    seq_num: u64,
+    signer_id: PeerId,
+    nonce: u64,
+    from_self: bool,
+) -> ParsedMessage {
+    let mut info = PbftMessageInfo::new_from(PbftMessageType::ViewChange, view, seq_num, signer_id);
+    info.set_nonce(nonce);
+    let mut msg = PbftMessage::new();
+    msg.set_info(info);
+
+    let mut parsed = ParsedMessage::from_pbft_message(msg).expect("Failed to parse PbftMessage");
+    parsed.from_self = from_self;
+    parsed
+}
+
+/// Assert that no two of the given nodes committed different blocks at the same sequence number
+/// (the core safety property of PBFT), based on each node's recent commits
+pub fn assert_no_conflicting_commits(nodes: &[&PbftNode]) {
+    let mut committed: HashMap<u64, &BlockId> = HashMap::new();
+    for node in nodes {
+        for (seq_num, block_id) in node.recent_commits() {
+            let existing = committed.entry(*seq_num).or_insert(block_id);
+            assert_eq!(
+                *existing, block_id,
+                "Conflicting blocks committed at sequence number {}",
+                seq_num
+            );
+        }
+    }
+}
@@ -17,9 +17,63 @@
 
 //! Timing-related structures
 
+use std::fmt;
+use std::sync::Arc;
+#[cfg(test)]
+use std::sync::Mutex;
 use std::thread::sleep;
 use std::time::{Duration, Instant};
 
+/// A source of the current time for timers
+pub trait Clock: fmt::Debug + Send + Sync {
+    fn now(&self) -> Instant;
+}
+
+/// The real time, as given by `Instant::now`
+#[derive(Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+/// A clock that only moves when it is advanced, so timeouts can be tested without sleeping
+#[cfg(test)]
+#[derive(Debug)]
+pub struct MockClock {
+    now: Mutex<Instant>,
+}
+
+#[cfg(test)]
+impl Default for MockClock {
+    fn default() -> Self {
+        MockClock {
+            now: Mutex::new(Instant::now()),
+        }
+    }
+}
+
+#[cfg(test)]
+impl MockClock {
+    /// Move the clock forward by the given duration
+    pub fn advance(&self, duration: Duration) {
+        *self.now.lock().expect("Mock clock lock poisoned") += duration;
+    }
+}
+
+#[cfg(test)]
+impl Clock for MockClock {
+    fn now(&self) -> Instant {
+        *self.now.lock().expect("Mock clock lock poisoned")
+    }
+}
+
+fn system_clock() -> Arc<dyn Clock> {
+    Arc::new(SystemClock)
+}
+
 /// Encapsulates calling a function every so often
 pub struct Ticker {
     last: Instant,
@@ -46,9 +100,12 @@ impl Ticker {
 
 #[derive(Debug, PartialEq, Serialize, Deserialize)]
 enum TimeoutState {
+    /// Older versions had a separate `Expired` state for timers that were active and had expired;
+    /// whether a timer has expired is now computed from its start time, so `Expired` is loaded as
+    /// `Active` to keep previously persisted state loadable
+    #[serde(alias = "Expired")]
     Active,
     Inactive,
-    Expired,
 }
 
 /// A timer that expires after a given duration
@@ -57,6 +114,9 @@ enum TimeoutState {
 pub struct Timeout {
     state: TimeoutState,
     duration: Duration,
+    /// Extra time added to the duration when the timer was last started
+    #[serde(default)]
+    jitter: Duration,
     #[serde(with = "serde_millis")]
     start: Instant,
 }
@@ -66,24 +126,36 @@ impl Timeout {
         Timeout {
             state: TimeoutState::Inactive,
             duration,
+            jitter: Duration::from_millis(0),
             start: Instant::now(),
         }
     }
 
-    /// Update the timer state, and check if the timer is expired
-    pub fn check_expired(&mut self) -> bool {
-        if self.state == TimeoutState::Active && Instant::now() - self.start > self.duration {
-            self.state = TimeoutState::Expired;
-        }
-        match self.state {
-            TimeoutState::Active | TimeoutState::Inactive => false,
-            TimeoutState::Expired => true,
-        }
+    /// Check if the timer is expired (it was started and its duration, plus any jitter, has
+    /// passed since then)
+    pub fn is_expired(&self) -> bool {
+        self.is_expired_at(Instant::now())
+    }
+
+    /// Check if the timer is expired as of the given time
+    fn is_expired_at(&self, now: Instant) -> bool {
+        self.state == TimeoutState::Active && now - self.start > self.duration + self.jitter
     }
 
     pub fn start(&mut self) {
+        self.start_with_jitter(Duration::from_millis(0));
+    }
+
+    /// Start the timer so that it expires after its duration plus the given jitter
+    pub fn start_with_jitter(&mut self, jitter: Duration) {
+        self.start_with_jitter_at(jitter, Instant::now());
+    }
+
+    /// Start the timer at the given time so that it expires after its duration plus the jitter
+    fn start_with_jitter_at(&mut self, jitter: Duration, now: Instant) {
         self.state = TimeoutState::Active;
-        self.start = Instant::now();
+        self.jitter = jitter;
+        self.start = now;
     }
 
     pub fn stop(&mut self) {
@@ -96,11 +168,160 @@ impl Timeout {
         self.duration
     }
 
+    #[cfg(test)]
+    pub fn jitter(&self) -> Duration {
+        self.jitter
+    }
+
     pub fn is_active(&self) -> bool {
         self.state == TimeoutState::Active
     }
 }
 
+/// The names of the timers that are owned by the `TimerManager`
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum TimerName {
+    /// Makes sure the primary publishes blocks in a timely manner
+    Idle,
+    /// Makes sure the network doesn't get stuck while performing consensus on a block
+    Commit,
+    /// Makes sure the new primary sends a `NewView` in a timely manner during a view change
+    ViewChange,
+}
+
+impl TimerName {
+    /// All timer names, in the order their expiration should be handled
+    pub const ALL: [TimerName; 3] = [TimerName::Idle, TimerName::Commit, TimerName::ViewChange];
+}
+
+/// Owns all of the named timers used by a node; since there is exactly one `Timeout` per name,
+/// at most one timer can be armed for each name at a time
+///
+/// The timers are serialized using the names of their original `PbftState` fields so previously
+/// persisted state can still be loaded.
+///
+/// Each time a timer is started, a pseudo-random jitter of up to `jitter_percent` of its duration
+/// is added, so timers that are started at the same time on different nodes (for instance, when
+/// the whole network stalls) don't all expire at once. The jitter is generated from a seed, so it
+/// is deterministic for a given seed (nodes should use different seeds).
+///
+/// The timers use the system clock unless another `Clock` is provided (which is not persisted).
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TimerManager {
+    #[serde(rename = "idle_timeout")]
+    idle: Timeout,
+    #[serde(rename = "commit_timeout")]
+    commit: Timeout,
+    #[serde(rename = "view_change_timeout")]
+    view_change: Timeout,
+    #[serde(default)]
+    jitter_percent: u64,
+    #[serde(default)]
+    jitter_state: u64,
+    #[serde(skip, default = "system_clock")]
+    clock: Arc<dyn Clock>,
+}
+
+impl TimerManager {
+    pub fn new(idle: Duration, commit: Duration, view_change: Duration) -> Self {
+        TimerManager {
+            idle: Timeout::new(idle),
+            commit: Timeout::new(commit),
+            view_change: Timeout::new(view_change),
+            jitter_percent: 0,
+            jitter_state: 0,
+            clock: system_clock(),
+        }
+    }
+
+    /// Use the given clock for the timers instead of the system clock
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Add a jitter of up to `percent` of a timer's duration each time the timer is started,
+    /// generated from the given seed
+    pub fn with_jitter(mut self, percent: u64, seed: u64) -> Self {
+        self.jitter_percent = percent;
+        self.jitter_state = seed;
+        self
+    }
+
+    /// Generate the jitter for a timer with the given duration (splitmix64)
+    fn next_jitter(&mut self, duration: Duration) -> Duration {
+        if self.jitter_percent == 0 {
+            return Duration::from_millis(0);
+        }
+
+        self.jitter_state = self.jitter_state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.jitter_state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^= z >> 31;
+
+        let max_jitter = duration.as_millis() as u64 * self.jitter_percent / 100;
+        Duration::from_millis(z % (max_jitter + 1))
+    }
+
+    fn get(&self, name: TimerName) -> &Timeout {
+        match name {
+            TimerName::Idle => &self.idle,
+            TimerName::Commit => &self.commit,
+            TimerName::ViewChange => &self.view_change,
+        }
+    }
+
+    fn get_mut(&mut self, name: TimerName) -> &mut Timeout {
+        match name {
+            TimerName::Idle => &mut self.idle,
+            TimerName::Commit => &mut self.commit,
+            TimerName::ViewChange => &mut self.view_change,
+        }
+    }
+
+    /// Arm the named timer; if it is already armed, it is restarted
+    pub fn start(&mut self, name: TimerName) {
+        let jitter = self.next_jitter(self.get(name).duration);
+        let now = self.clock.now();
+        self.get_mut(name).start_with_jitter_at(jitter, now);
+    }
+
+    /// Disarm the named timer
+    pub fn stop(&mut self, name: TimerName) {
+        self.get_mut(name).stop();
+    }
+
+    pub fn is_active(&self, name: TimerName) -> bool {
+        self.get(name).is_active()
+    }
+
+    /// Disarm the named timer and change the duration it will use the next time it is started
+    pub fn set_duration(&mut self, name: TimerName, duration: Duration) {
+        *self.get_mut(name) = Timeout::new(duration);
+    }
+
+    #[cfg(test)]
+    pub fn duration(&self, name: TimerName) -> Duration {
+        self.get(name).duration()
+    }
+
+    #[cfg(test)]
+    pub fn jitter(&self, name: TimerName) -> Duration {
+        self.get(name).jitter()
+    }
+
+    /// Get the names of all timers that have expired
+    pub fn expired_timers(&self) -> Vec<TimerName> {
+        let now = self.clock.now();
+        TimerName::ALL
+            .iter()
+            .cloned()
+            .filter(|name| self.get(*name).is_expired_at(now))
+            .collect()
+    }
+}
+
 /// With exponential backoff, repeatedly try the callback until the result is `Ok`
 pub fn retry_until_ok<T, E, F: FnMut() -> Result<T, E>>(
     base: Duration,
@@ -185,13 +406,115 @@ mod tests {
         assert_eq!(t.state, TimeoutState::Active);
         ::std::thread::sleep(Duration::from_millis(110));
 
-        assert!(t.check_expired());
-        assert_eq!(t.state, TimeoutState::Expired);
+        assert!(t.is_expired());
+        assert_eq!(t.state, TimeoutState::Active);
 
         t.stop();
         assert_eq!(t.state, TimeoutState::Inactive);
     }
 
+    /// The `TimerManager` owns all of a node's named timers and reports which of them have
+    /// expired. Arm the idle timer with a short duration and the commit timer with a long
+    /// duration (leaving the view change timer disarmed), wait for the short duration to pass,
+    /// and check that only the idle timer is reported as expired. Also check that stopping and
+    /// restarting a timer works as expected.
+    #[test]
+    fn timer_manager() {
+        let mut timers = TimerManager::new(
+            Duration::from_millis(50),
+            Duration::from_millis(10_000),
+            Duration::from_millis(50),
+        );
+        assert!(timers.expired_timers().is_empty());
+
+        timers.start(TimerName::Idle);
+        timers.start(TimerName::Commit);
+        assert!(timers.is_active(TimerName::Idle));
+        assert!(timers.is_active(TimerName::Commit));
+        assert!(!timers.is_active(TimerName::ViewChange));
+        ::std::thread::sleep(Duration::from_millis(60));
+
+        assert_eq!(vec![TimerName::Idle], timers.expired_timers());
+
+        // Restarting the idle timer resets its deadline; stopping it disarms it
+        timers.start(TimerName::Idle);
+        assert!(timers.expired_timers().is_empty());
+        timers.stop(TimerName::Idle);
+        assert!(!timers.is_active(TimerName::Idle));
+
+        // Changing the duration of a timer disarms it
+        timers.set_duration(TimerName::Commit, Duration::from_millis(10));
+        assert!(!timers.is_active(TimerName::Commit));
+        assert_eq!(
+            Duration::from_millis(10),
+            timers.duration(TimerName::Commit)
+        );
+        timers.start(TimerName::Commit);
+        timers.start(TimerName::ViewChange);
+        ::std::thread::sleep(Duration::from_millis(60));
+        assert_eq!(
+            vec![TimerName::Commit, TimerName::ViewChange],
+            timers.expired_timers()
+        );
+    }
+
+    /// With jitter enabled, the `TimerManager` adds a pseudo-random amount of up to the configured
+    /// percentage of a timer's duration each time the timer is started. Check that the jitter is
+    /// within bounds, that it varies between starts and between seeds (so different nodes' timers
+    /// differ), and that it is the same for the same seed. Also check that no jitter is added when
+    /// it is disabled.
+    #[test]
+    fn timer_jitter() {
+        let duration = Duration::from_millis(10_000);
+        let new_timers = |percent, seed| {
+            TimerManager::new(duration, duration, duration).with_jitter(percent, seed)
+        };
+        let jitters = |timers: &mut TimerManager| {
+            (0..10)
+                .map(|_| {
+                    timers.start(TimerName::ViewChange);
+                    timers.jitter(TimerName::ViewChange)
+                })
+                .collect::<Vec<_>>()
+        };
+
+        let node1 = jitters(&mut new_timers(10, 1));
+        let node2 = jitters(&mut new_timers(10, 2));
+        assert!(node1
+            .iter()
+            .chain(node2.iter())
+            .all(|jitter| *jitter <= Duration::from_millis(1000)));
+        assert!(node1.iter().any(|jitter| *jitter != node1[0]));
+        assert_ne!(node1, node2);
+        assert_eq!(node1, jitters(&mut new_timers(10, 1)));
+
+        assert!(jitters(&mut new_timers(0, 1))
+            .iter()
+            .all(|jitter| *jitter == Duration::from_millis(0)));
+    }
+
+    /// With a `MockClock`, the `TimerManager`'s timers only expire when the clock is advanced, so
+    /// timeouts can be checked deterministically. Arm the view change timer, advance the clock to
+    /// just before its duration has passed and check that it hasn't expired, then advance the
+    /// clock past the duration and check that it has expired.
+    #[test]
+    fn timer_manager_mock_clock() {
+        let clock = Arc::new(MockClock::default());
+        let duration = Duration::from_millis(10_000);
+        let mut timers = TimerManager::new(duration, duration, duration).with_clock(clock.clone());
+
+        timers.start(TimerName::ViewChange);
+        clock.advance(Duration::from_millis(9_999));
+        assert!(timers.expired_timers().is_empty());
+
+        clock.advance(Duration::from_millis(2));
+        assert_eq!(vec![TimerName::ViewChange], timers.expired_timers());
+
+        // Restarting the timer resets its deadline to the clock's current time
+        timers.start(TimerName::ViewChange);
+        assert!(timers.expired_timers().is_empty());
+    }
+
     /// Retry a function that fails three times and succeeds on the 4th try with the
     /// `retry_until_ok` method, a 10ms base, and 20ms max; the total time should be 50ms.
     #[test]